@@ -0,0 +1,11 @@
+fn main() {
+    println!("cargo::rerun-if-changed=build.rs");
+    println!("cargo::rustc-check-cfg=cfg(picoalloc_linux_syscalls)");
+
+    // Set when the Linux environments, which talk to the kernel through raw syscalls, are available.
+    let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let target_arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    if target_os == "linux" && matches!(target_arch.as_str(), "x86_64" | "x86" | "aarch64" | "arm" | "riscv64") {
+        println!("cargo::rustc-cfg=picoalloc_linux_syscalls");
+    }
+}
@@ -0,0 +1,29 @@
+#!/bin/bash
+
+set -euo pipefail
+cd -- "$(dirname -- "${BASH_SOURCE[0]}")"
+cd ../..
+
+# The tests are run through QEMU's user mode emulation, with the libraries of the cross toolchains.
+export CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_LINKER=aarch64-linux-gnu-gcc
+export CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_RUNNER="qemu-aarch64 -L /usr/aarch64-linux-gnu"
+export CARGO_TARGET_RISCV64GC_UNKNOWN_LINUX_GNU_LINKER=riscv64-linux-gnu-gcc
+export CARGO_TARGET_RISCV64GC_UNKNOWN_LINUX_GNU_RUNNER="qemu-riscv64 -L /usr/riscv64-linux-gnu"
+export CARGO_TARGET_I686_UNKNOWN_LINUX_GNU_LINKER=i686-linux-gnu-gcc
+export CARGO_TARGET_I686_UNKNOWN_LINUX_GNU_RUNNER="qemu-i386 -L /usr/i686-linux-gnu"
+export CARGO_TARGET_ARMV7_UNKNOWN_LINUX_GNUEABIHF_LINKER=arm-linux-gnueabihf-gcc
+export CARGO_TARGET_ARMV7_UNKNOWN_LINUX_GNUEABIHF_RUNNER="qemu-arm -L /usr/arm-linux-gnueabihf"
+
+for TARGET in aarch64-unknown-linux-gnu riscv64gc-unknown-linux-gnu i686-unknown-linux-gnu armv7-unknown-linux-gnueabihf; do
+    echo ">> cargo test (paranoid, $TARGET)"
+    cargo test -p picoalloc --target=$TARGET --features paranoid
+
+    echo ">> cargo test (paranoid, global allocator, $TARGET)"
+    cargo test -p picoalloc --target=$TARGET --features paranoid,global_allocator_rust
+
+    echo ">> cargo test (paranoid, shared heap, $TARGET)"
+    cargo test -p picoalloc --target=$TARGET --features paranoid,shared_heap
+
+    echo ">> cargo test (paranoid, persistent heap, $TARGET)"
+    cargo test -p picoalloc --target=$TARGET --features paranoid,persistent_heap
+done
@@ -16,9 +16,30 @@ cargo test --features paranoid
 echo ">> cargo test (paranoid, global allocator)"
 cargo test --features paranoid,global_allocator_rust
 
+echo ">> cargo test (paranoid, std)"
+cargo test --features paranoid,std
+
+echo ">> cargo test (paranoid, shared heap)"
+cargo test --features paranoid,shared_heap
+
+echo ">> cargo test (paranoid, persistent heap)"
+cargo test --features paranoid,persistent_heap
+
+echo ">> cargo test (paranoid, relocatable)"
+cargo test --features paranoid,relocatable
+
+echo ">> cargo test (paranoid, heap profiler)"
+cargo test --features paranoid,heap_profiler
+
+echo ">> cargo test (paranoid, lock_api)"
+cargo test --features paranoid,lock_api
+
 echo ">> cargo build (native)"
 cargo build -p picoalloc_native --release
 
+echo ">> cargo build (native, heap profiler)"
+RUSTFLAGS="-C force-frame-pointers=yes" cargo build -p picoalloc_native --release --features heap_profiler
+
 echo ">> cargo check (PolkaVM)"
 RUSTC_BOOTSTRAP=1 cargo check --target=ci/riscv64emac-unknown-none-polkavm.json -Z build-std=core
 
@@ -9,6 +9,7 @@ cd ..
 
 case "$OSTYPE" in
   linux*)
+    ./ci/jobs/build-and-test-cross.sh
     ./ci/jobs/fuzz.sh
   ;;
 esac
@@ -0,0 +1,2 @@
+// Uses the same `cfg`s as the allocator itself, so that only the environments it provides are used.
+include!("../build.rs");
@@ -0,0 +1,305 @@
+use picoalloc::{Allocator, Array, ArrayPointer, Env, Fragmentation, TraceEvent, TRACE_MAGIC};
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::time::{Duration, Instant};
+
+const BUFFER_SIZE: usize = 256 * 1024 * 1024;
+
+#[cfg(picoalloc_linux_syscalls)]
+const SYSTEM_SIZE: usize = 1024 * 1024 * 1024;
+
+#[derive(Default)]
+struct Report {
+    events: usize,
+    failed: usize,
+    elapsed: Duration,
+    peak_allocated_space: u64,
+    final_allocated_space: u64,
+    peak_live_bytes: u64,
+    live_bytes: u64,
+    fragmentation: Fragmentation,
+}
+
+fn decode_trace(bytes: &[u8]) -> Result<Vec<TraceEvent>, String> {
+    let Some(mut bytes) = bytes.strip_prefix(&TRACE_MAGIC) else {
+        return Err("not a picoalloc trace: invalid magic".into());
+    };
+
+    let mut events = Vec::new();
+    while !bytes.is_empty() {
+        let Some((event, length)) = TraceEvent::decode(bytes) else {
+            return Err(format!("malformed event #{}", events.len()));
+        };
+
+        events.push(event);
+        bytes = &bytes[length..];
+    }
+
+    Ok(events)
+}
+
+fn replay<E: Env>(allocator: &mut Allocator<E>, events: &[TraceEvent]) -> Report {
+    let mut report = Report {
+        events: events.len(),
+        ..Report::default()
+    };
+
+    let mut live: HashMap<u32, NonNull<u8>> = HashMap::new();
+    let usable_size = |pointer: NonNull<u8>| unsafe { Allocator::<E>::usable_size(pointer) } as u64;
+
+    let timestamp = Instant::now();
+    for event in events {
+        match *event {
+            TraceEvent::Alloc {
+                align,
+                size,
+                zeroed,
+                result,
+            } => {
+                let pointer = if zeroed {
+                    allocator.alloc_zeroed(align, size)
+                } else {
+                    allocator.alloc(align, size)
+                };
+
+                match (pointer, result) {
+                    (Some(pointer), Some(handle)) => {
+                        report.live_bytes += usable_size(pointer);
+                        live.insert(handle, pointer);
+                    }
+                    (Some(pointer), None) => unsafe { allocator.free(pointer) },
+                    (None, Some(_)) => report.failed += 1,
+                    (None, None) => {}
+                }
+            }
+            TraceEvent::Free { handle } => {
+                if let Some(pointer) = live.remove(&handle) {
+                    report.live_bytes -= usable_size(pointer);
+                    unsafe { allocator.free(pointer) }
+                }
+            }
+            TraceEvent::Realloc {
+                handle,
+                align,
+                size,
+                result,
+            } => {
+                let Some(pointer) = live.remove(&handle) else {
+                    continue;
+                };
+
+                report.live_bytes -= usable_size(pointer);
+                let new_pointer = if size.bytes() == 0 && result.is_none() {
+                    unsafe { allocator.realloc(pointer, align, size) }
+                } else {
+                    unsafe { allocator.try_realloc(pointer, align, size) }.ok()
+                };
+
+                match new_pointer {
+                    Some(new_pointer) => {
+                        report.live_bytes += usable_size(new_pointer);
+                        live.insert(result.unwrap_or(handle), new_pointer);
+                        if result.is_none() {
+                            report.failed += 1;
+                        }
+                    }
+                    None if size.bytes() == 0 => {}
+                    None => {
+                        report.live_bytes += usable_size(pointer);
+                        live.insert(handle, pointer);
+                        if result.is_some() {
+                            report.failed += 1;
+                        }
+                    }
+                }
+            }
+            TraceEvent::Grow { handle, size, result } => {
+                let Some(&pointer) = live.get(&handle) else {
+                    continue;
+                };
+
+                let old_size = usable_size(pointer);
+                let new_size = unsafe { allocator.grow_inplace(pointer, size) };
+                report.live_bytes = report.live_bytes - old_size + usable_size(pointer);
+                if new_size.is_none() && result.is_some() {
+                    report.failed += 1;
+                }
+            }
+            TraceEvent::Shrink { handle, size } => {
+                let Some(&pointer) = live.get(&handle) else {
+                    continue;
+                };
+
+                let old_size = usable_size(pointer);
+                unsafe { allocator.shrink_inplace(pointer, size) };
+                if size.bytes() == 0 {
+                    live.remove(&handle);
+                    report.live_bytes -= old_size;
+                } else {
+                    report.live_bytes = report.live_bytes - old_size + usable_size(pointer);
+                }
+            }
+            TraceEvent::Reset => {
+                unsafe { allocator.reset() };
+                live.clear();
+                report.live_bytes = 0;
+            }
+        }
+
+        report.peak_live_bytes = report.peak_live_bytes.max(report.live_bytes);
+        report.peak_allocated_space = report.peak_allocated_space.max(u64::from(allocator.allocated_space().bytes()));
+    }
+
+    report.elapsed = timestamp.elapsed();
+    report.final_allocated_space = u64::from(allocator.allocated_space().bytes());
+    report.fragmentation = allocator.fragmentation();
+
+    for pointer in live.into_values() {
+        unsafe { allocator.free(pointer) }
+    }
+
+    report
+}
+
+fn print_report(report: &Report) {
+    let overhead = if report.final_allocated_space == 0 {
+        0.0
+    } else {
+        1.0 - report.live_bytes as f64 / report.final_allocated_space as f64
+    };
+
+    println!("Events:                 {}", report.events);
+    println!("Failed on replay:       {}", report.failed);
+    println!(
+        "Time:                   {:.3} ms ({:.1} ns/event)",
+        report.elapsed.as_secs_f64() * 1000.0,
+        report.elapsed.as_nanos() as f64 / report.events.max(1) as f64
+    );
+    println!("Peak allocated space:   {} bytes", report.peak_allocated_space);
+    println!("Final allocated space:  {} bytes", report.final_allocated_space);
+    println!("Peak live bytes:        {} bytes", report.peak_live_bytes);
+    println!("Final live bytes:       {} bytes", report.live_bytes);
+    println!("Committed overhead:     {:.2}%", overhead * 100.0);
+    println!("Free chunks:            {}", report.fragmentation.free_chunks);
+    println!("Largest free chunk:     {} bytes", report.fragmentation.largest_free_chunk);
+    println!(
+        "External fragmentation: {:.2}%",
+        report.fragmentation.external_fragmentation() * 100.0
+    );
+}
+
+fn main() {
+    let mut env_kind = String::from("buffer");
+    let mut path = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--env" => {
+                env_kind = args.next().unwrap_or_default();
+            }
+            "--help" | "-h" => {
+                println!("usage: picoalloc_replay [--env buffer|system] <trace>");
+                return;
+            }
+            _ => path = Some(arg),
+        }
+    }
+
+    let Some(path) = path else {
+        eprintln!("usage: picoalloc_replay [--env buffer|system] <trace>");
+        std::process::exit(1);
+    };
+
+    let events = match std::fs::read(&path)
+        .map_err(|error| error.to_string())
+        .and_then(|bytes| decode_trace(&bytes))
+    {
+        Ok(events) => events,
+        Err(error) => {
+            eprintln!("failed to load {path}: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    let report = match env_kind.as_str() {
+        "buffer" => {
+            let layout = std::alloc::Layout::new::<Array<BUFFER_SIZE>>();
+            let buffer = unsafe { std::alloc::alloc_zeroed(layout) }.cast::<Array<BUFFER_SIZE>>();
+            if buffer.is_null() {
+                std::alloc::handle_alloc_error(layout);
+            }
+
+            let report = {
+                let mut allocator = Allocator::new(unsafe { ArrayPointer::new(buffer) });
+                replay(&mut allocator, &events)
+            };
+
+            unsafe { std::alloc::dealloc(buffer.cast(), layout) };
+            report
+        }
+        #[cfg(picoalloc_linux_syscalls)]
+        "system" => {
+            let mut allocator = Allocator::new(picoalloc::UnsafeSystem::<SYSTEM_SIZE>);
+            replay(&mut allocator, &events)
+        }
+        _ => {
+            eprintln!("unsupported env: {env_kind}");
+            std::process::exit(1);
+        }
+    };
+
+    print_report(&report);
+}
+
+#[test]
+fn test_replay() {
+    use picoalloc::Size;
+
+    let mut trace = TRACE_MAGIC.to_vec();
+    let one = Size::from_bytes_usize(1).unwrap();
+    let events = [
+        TraceEvent::Alloc {
+            align: one,
+            size: Size::from_bytes_usize(100).unwrap(),
+            zeroed: false,
+            result: Some(1),
+        },
+        TraceEvent::Alloc {
+            align: one,
+            size: Size::from_bytes_usize(64).unwrap(),
+            zeroed: true,
+            result: Some(6),
+        },
+        TraceEvent::Realloc {
+            handle: 1,
+            align: one,
+            size: Size::from_bytes_usize(200).unwrap(),
+            result: Some(9),
+        },
+        TraceEvent::Free { handle: 6 },
+        TraceEvent::Reset,
+        TraceEvent::Alloc {
+            align: one,
+            size: Size::from_bytes_usize(32).unwrap(),
+            zeroed: false,
+            result: Some(1),
+        },
+    ];
+
+    for event in events {
+        let mut buffer = [0; TraceEvent::MAX_ENCODED_LENGTH];
+        let length = event.encode(&mut buffer);
+        trace.extend_from_slice(&buffer[..length]);
+    }
+
+    let decoded = decode_trace(&trace).unwrap();
+    assert_eq!(decoded, events);
+
+    let mut buffer = Array([0; 4096]);
+    let mut allocator = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+    let report = replay(&mut allocator, &decoded);
+    assert_eq!(report.failed, 0);
+    assert_eq!(report.live_bytes, 32);
+    assert_eq!(report.peak_live_bytes, 288);
+}
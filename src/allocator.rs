@@ -1,6 +1,7 @@
 #![allow(clippy::unnecessary_cast)]
 
 use crate::Env;
+use core::mem::MaybeUninit;
 use core::ptr::NonNull;
 
 #[cfg(any(test, feature = "paranoid"))]
@@ -31,9 +32,17 @@ macro_rules! paranoid_assert_eq {
     ($lhs:expr, $rhs:expr) => {};
 }
 
+mod snapshot;
+
+pub use self::snapshot::SnapshotError;
+
 const MAX_ALLOCATION_SIZE: Size = Size::from_bytes_usize(1024 * 1024 * 1024).unwrap();
 const MAX_BINS: u32 = 4096;
 
+/// Allocations aligned to at least this much look for a chunk which contains a suitably aligned address
+/// instead of asking for a chunk big enough to fit any alignment padding.
+const LARGE_ALIGNMENT: Size = Size::from_bytes_usize(4096).unwrap();
+
 type SizeT = u32;
 const ALLOCATION_GRANULARITY: SizeT = 32;
 const ALLOCATION_SIZE_SHIFT: u32 = ALLOCATION_GRANULARITY.ilog2();
@@ -66,6 +75,18 @@ impl Size {
         self.0 << ALLOCATION_SIZE_SHIFT
     }
 
+    #[cfg(feature = "trace")]
+    #[inline]
+    pub(crate) const fn from_granules(granules: SizeT) -> Self {
+        Self(granules)
+    }
+
+    #[cfg(feature = "trace")]
+    #[inline]
+    pub(crate) const fn granules(self) -> SizeT {
+        self.0
+    }
+
     #[inline]
     fn checked_add(self, rhs: Size) -> Option<Self> {
         self.0.checked_add(rhs.0).map(Size)
@@ -113,7 +134,7 @@ pub type Address = u32;
 ))]
 pub type Address = u64;
 
-#[cfg(any(target_arch = "x86", target_arch = "riscv32"))]
+#[cfg(any(target_arch = "x86", target_arch = "arm", target_arch = "riscv32"))]
 type Mask = u32;
 
 #[cfg(any(
@@ -209,6 +230,33 @@ const fn to_bin_index<const ROUND_UP: bool>(size: Size) -> u32 {
     to_bin_index_generic::<MANTISSA_BITS, ROUND_UP>(size.0)
 }
 
+/// Returns the smallest size which is put into the bin with the given `index`; the inverse of `to_bin_index_generic::<_, false>`.
+const fn bin_index_to_size_generic<const MANTISSA_BITS: u32>(index: u32) -> SizeT {
+    let mantissa_value = 1 << MANTISSA_BITS;
+    if index < mantissa_value {
+        return (index + 1) as SizeT;
+    }
+
+    let exponent = (index + 1) >> MANTISSA_BITS;
+    let mantissa = (index + 1) & (mantissa_value - 1);
+    ((mantissa_value | mantissa) as SizeT) << (exponent - 1)
+}
+
+const fn bin_index_to_size(index: u32) -> Size {
+    const MANTISSA_BITS: u32 = BIN_CONFIG.mantissa_bits;
+    Size(bin_index_to_size_generic::<MANTISSA_BITS>(index))
+}
+
+const _: () = {
+    let mut index = 0;
+    while index < BIN_CONFIG.bin_count {
+        let size = bin_index_to_size(index);
+        assert!(to_bin_index::<false>(size) == index);
+        assert!(to_bin_index::<false>(Size(size.0 - 1)) < index || index == 0);
+        index += 1;
+    }
+};
+
 const SECONDARY_LENGTH: usize = {
     let bits = BIN_CONFIG.bin_count as usize;
     let bits_per_item = ::core::mem::size_of::<Mask>() * 8;
@@ -248,10 +296,9 @@ struct BitIndex {
 
 impl PartialEq for BitIndex {
     fn eq(&self, rhs: &Self) -> bool {
-        let is_equal = self.index == rhs.index;
-        paranoid_assert_eq!(is_equal, self.primary == rhs.primary);
-        paranoid_assert_eq!(is_equal, self.secondary == rhs.secondary);
-        is_equal
+        paranoid_assert!((self.index == rhs.index) == (self.primary == rhs.primary));
+        paranoid_assert!((self.index == rhs.index) == (self.secondary == rhs.secondary));
+        self.index == rhs.index
     }
 }
 
@@ -320,6 +367,13 @@ impl BitMask {
         }
     }
 
+    /// Returns whether the bit at `index` is set.
+    #[inline]
+    const fn is_set(&self, index: BitIndex) -> bool {
+        let secondary = unsafe { *get_unchecked(&self.secondary_masks, index.primary as usize) };
+        (secondary & (1 << index.secondary)) != 0
+    }
+
     /// Finds the first set bit, starting at `min_index`.
     #[inline]
     const fn find_first(&self, min_index: BitIndex) -> Option<BitIndex> {
@@ -400,6 +454,7 @@ unsafe impl<T> Sync for Pointer<T> {}
 unsafe impl<T> Send for Pointer<T> {}
 
 impl<T> Pointer<T> {
+    #[cfg(feature = "relocatable")]
     const NULL: Self = Self::from_address(0);
 
     #[inline]
@@ -415,6 +470,7 @@ impl<T> Pointer<T> {
         self.raw
     }
 
+    #[cfg(any(test, feature = "paranoid"))]
     #[inline]
     fn is_null(self) -> bool {
         self.raw == 0
@@ -436,6 +492,15 @@ impl<T> Pointer<T> {
         }
     }
 
+    #[cfg(feature = "relocatable")]
+    #[inline]
+    fn unchecked_add_bytes(self, offset: Address) -> Self {
+        Pointer {
+            raw: self.raw.wrapping_add(offset),
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
     #[inline]
     fn unchecked_sub(self, offset: Size) -> Self {
         Pointer {
@@ -541,6 +606,72 @@ impl ChunkSize {
 struct ChunkHeader {
     prev_chunk_size: Size,
     size: ChunkSize,
+    /// How many bytes at the end of an allocated chunk weren't requested; overlaps the links of a free chunk.
+    unused_bytes: u32,
+}
+
+/// A link to a free chunk in a free list.
+///
+/// With the `relocatable` feature this is an offset relative to the base address, so that the heap
+/// can be moved to a different address; otherwise it's an absolute address.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(transparent)]
+struct Link(Address);
+
+impl Link {
+    #[cfg(not(feature = "relocatable"))]
+    const NULL: Self = Link(0);
+
+    // The offset of the very first chunk is zero, so a different value must be used as the sentinel.
+    #[cfg(feature = "relocatable")]
+    const NULL: Self = Link(Address::MAX);
+
+    #[inline]
+    fn is_null(self) -> bool {
+        self == Self::NULL
+    }
+
+    /// Creates a link to the free chunk at `offset` from the start of the heap.
+    #[inline]
+    fn from_offset(offset: Size, base_address: *mut u8) -> Self {
+        Link::new(Pointer::from_pointer_mut(base_address).unchecked_add(offset).cast(), base_address)
+    }
+
+    /// Returns the offset of the linked chunk from the start of the heap, in bytes.
+    #[inline]
+    fn offset(self, base_address: *mut u8) -> Address {
+        self.get(base_address)
+            .address()
+            .wrapping_sub(Pointer::from_pointer_mut(base_address).address())
+    }
+
+    #[cfg(not(feature = "relocatable"))]
+    #[inline]
+    fn new(chunk: Pointer<FreeChunkHeader>, _base_address: *mut u8) -> Self {
+        Link(chunk.address())
+    }
+
+    #[cfg(not(feature = "relocatable"))]
+    #[inline]
+    fn get(self, _base_address: *mut u8) -> Pointer<FreeChunkHeader> {
+        Pointer::from_address(self.0)
+    }
+
+    #[cfg(feature = "relocatable")]
+    #[inline]
+    fn new(chunk: Pointer<FreeChunkHeader>, base_address: *mut u8) -> Self {
+        Link(chunk.address().wrapping_sub(Pointer::from_pointer_mut(base_address).address()))
+    }
+
+    #[cfg(feature = "relocatable")]
+    #[inline]
+    fn get(self, base_address: *mut u8) -> Pointer<FreeChunkHeader> {
+        if self.is_null() {
+            return Pointer::NULL;
+        }
+
+        Pointer::from_pointer_mut(base_address).unchecked_add_bytes(self.0).cast()
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -548,8 +679,8 @@ struct ChunkHeader {
 struct FreeChunkHeader {
     prev_chunk_size: Size,
     size: ChunkSize,
-    next_in_list: Pointer<FreeChunkHeader>,
-    prev_in_list: Pointer<FreeChunkHeader>,
+    next_in_list: Link,
+    prev_in_list: Link,
 }
 
 const HEADER_SIZE: Size = Size::from_bytes_usize(core::mem::size_of::<ChunkHeader>()).unwrap();
@@ -561,11 +692,246 @@ const _: () = {
     assert!(HEADER_SIZE.0 == 1);
 };
 
+/// Keeps the stores to the chunk headers from being reordered across this point.
+///
+/// The headers are written in an order which keeps the chunks walkable after every store,
+/// so that a persistent heap can be recovered if the process dies in the middle of an operation.
+#[inline(always)]
+fn header_store_barrier() {
+    #[cfg(feature = "persistent_heap")]
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::Release);
+
+    #[cfg(all(test, feature = "persistent_heap"))]
+    interrupt::tick();
+}
+
+/// Simulates the process dying in the middle of an allocator operation.
+#[cfg(all(test, feature = "persistent_heap"))]
+pub(crate) mod interrupt {
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static STORES_LEFT: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    /// Makes the allocator unwind once it reaches the given number of ordered header stores.
+    pub(crate) fn after_stores(count: Option<usize>) {
+        STORES_LEFT.set(count);
+    }
+
+    pub(super) fn tick() {
+        match STORES_LEFT.get() {
+            Some(0) => {
+                STORES_LEFT.set(None);
+                std::panic::resume_unwind(std::boxed::Box::new(()));
+            }
+            Some(count) => STORES_LEFT.set(Some(count - 1)),
+            None => {}
+        }
+    }
+}
+
+/// The reason why an allocation has failed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AllocError {
+    /// The requested alignment is zero or is not a power of two.
+    InvalidAlignment,
+    /// The requested size, together with the chunk header and the alignment padding, is bigger than the maximum allocation size.
+    SizeOverflow,
+    /// The environment failed to allocate the address space for the heap.
+    InitializationFailed,
+    /// There is no free chunk which is big enough.
+    OutOfSpace,
+    /// The environment refused to make more memory accessible through [`Env::expand_memory_until`](Env::expand_memory_until).
+    ExpansionRefused,
+    /// The allocation would exceed the budget set through [`Allocator::set_budget`](Allocator::set_budget).
+    BudgetExceeded,
+}
+
+impl AllocError {
+    /// Returns whether the allocation has failed due to running out of memory, as opposed to being an invalid request.
+    pub fn is_out_of_memory(self) -> bool {
+        !matches!(self, AllocError::InvalidAlignment | AllocError::SizeOverflow)
+    }
+}
+
+impl core::fmt::Display for AllocError {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let message = match self {
+            AllocError::InvalidAlignment => "invalid alignment",
+            AllocError::SizeOverflow => "allocation size is too big",
+            AllocError::InitializationFailed => "failed to allocate the address space",
+            AllocError::OutOfSpace => "no free chunk is big enough",
+            AllocError::ExpansionRefused => "failed to expand the accessible memory",
+            AllocError::BudgetExceeded => "memory budget exceeded",
+        };
+
+        fmt.write_str(message)
+    }
+}
+
+/// Details of a failed allocation, passed to the [`OutOfMemoryHandler`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct OutOfMemory {
+    /// The requested alignment.
+    pub align: Size,
+    /// The requested size.
+    pub size: Size,
+    /// Whether zeroed memory was requested.
+    pub zeroed: bool,
+    /// The reason why the allocation has failed.
+    pub error: AllocError,
+    /// The total usable space of all of the live allocations.
+    pub used_space: Size,
+    /// How much of the address space is currently accessible.
+    pub allocated_space: Size,
+    /// The total size of the address space.
+    pub total_space: Size,
+}
+
+/// A callback which is called when an allocation fails due to running out of memory.
+///
+/// The handler can free memory through the allocator it receives; if it returns `true` the allocation is retried once.
+pub type OutOfMemoryHandler<E> = fn(&mut Allocator<E>, &OutOfMemory) -> bool;
+
+/// How a free chunk is picked for a new allocation.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum FitStrategy {
+    /// Takes the first chunk of the smallest bin which is guaranteed to fit, or else of the bin below it if that chunk fits.
+    ///
+    /// This always takes constant time.
+    #[default]
+    FirstInBin,
+    /// Like [`FitStrategy::FirstInBin`], but looks at up to this many chunks of the bin below, picking the smallest one which fits.
+    BoundedSearch(u32),
+    /// Picks the smallest chunk which fits, and the one with the lowest address out of those which are equally big.
+    ///
+    /// This can walk the whole free list of up to two bins.
+    BestFit,
+    /// Picks the chunk with the lowest address which fits.
+    ///
+    /// This keeps the allocations as low as possible, so that [`Allocator::allocated_space`](Allocator::allocated_space)
+    /// grows only when there is no other choice, which matters when the memory can't be given back to the system.
+    /// This walks the free lists of every bin which can fit the allocation.
+    LowestAddress,
+}
+
+/// Statistics about the free chunks in a single bin.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BinStats {
+    /// The index of the bin.
+    pub index: u32,
+    /// The smallest chunk size which is put into this bin, including the chunk header.
+    pub min_chunk_size: Size,
+    /// The number of free chunks in this bin.
+    pub free_chunks: usize,
+    /// The total size of the free chunks in this bin, in bytes, including their headers.
+    pub free_space: u64,
+}
+
+/// An iterator over the non-empty bins of an allocator.
+pub struct FreeBins<'a, E: Env> {
+    allocator: &'a Allocator<E>,
+    next_index: u32,
+}
+
+impl<E: Env> Iterator for FreeBins<'_, E> {
+    type Item = BinStats;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_index >= BIN_CONFIG.bin_count {
+            return None;
+        }
+
+        let bin = self
+            .allocator
+            .free_lists_with_unallocated_memory
+            .find_first(BitMask::index(self.next_index))?;
+        self.next_index = bin.index + 1;
+
+        let mut stats = BinStats {
+            index: bin.index,
+            min_chunk_size: bin_index_to_size(bin.index),
+            free_chunks: 0,
+            free_space: 0,
+        };
+
+        let mut chunk = self.allocator.first_in_free_list[bin.index()];
+        while !chunk.is_null() {
+            let chunk_ref = unsafe { chunk.get(self.allocator.base_address).get_unchecked(self.allocator.base_address) };
+            stats.free_chunks += 1;
+            stats.free_space += u64::from(chunk_ref.size.size().bytes());
+            chunk = chunk_ref.next_in_list;
+        }
+
+        Some(stats)
+    }
+}
+
+/// A summary of how the heap is laid out.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Fragmentation {
+    /// The number of allocated chunks.
+    pub allocated_chunks: usize,
+    /// The total usable size of all allocated chunks, in bytes.
+    pub allocated_space: u64,
+    /// The number of free chunks.
+    pub free_chunks: usize,
+    /// The total size of all free chunks, in bytes, including their headers.
+    pub free_space: u64,
+    /// How much of `free_space` lies beyond [`Allocator::allocated_space`](Allocator::allocated_space), in bytes.
+    pub uncommitted_free_space: u64,
+    /// The size of the largest free chunk, in bytes, including its header.
+    pub largest_free_chunk: u64,
+    /// The space taken by the headers of the allocated chunks, in bytes.
+    pub header_overhead: u64,
+    /// How much of `allocated_space` wasn't requested, in bytes.
+    ///
+    /// This is the rounding up of requests to the allocation granularity, which is only known for allocations
+    /// made through `GlobalAlloc` or the libc functions, plus any free space absorbed into an allocation.
+    pub rounding_waste: u64,
+    /// How much of `free_space` is in free chunks which have no room for any data, in bytes.
+    ///
+    /// These are left over when an allocation has to be aligned, and can't be used until their neighbours are freed.
+    pub padding_waste: u64,
+}
+
+/// Details of the chunk which contains a given pointer, returned by [`Allocator::allocation_info`](Allocator::allocation_info).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AllocInfo {
+    /// The start of the chunk's usable memory; for allocated chunks this is the pointer which was returned by the allocator.
+    pub start: NonNull<u8>,
+    /// The usable size of the chunk, in bytes.
+    pub usable_size: usize,
+    /// Whether the chunk is allocated.
+    pub is_allocated: bool,
+    /// The offset of the pointer from `start`, in bytes.
+    pub offset: usize,
+}
+
+impl Fragmentation {
+    /// Returns the external fragmentation ratio, from 0.0 (all of the free space is in a single chunk) to 1.0.
+    pub fn external_fragmentation(&self) -> f64 {
+        if self.free_space == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free_chunk as f64 / self.free_space as f64
+        }
+    }
+}
+
 pub struct Allocator<E: Env> {
     allocated_space: Size,
+    used_space: Size,
+    allocation_count: SizeT,
+    budget: Option<Size>,
+    out_of_memory_handler: Option<OutOfMemoryHandler<E>>,
+    /// Set when the handler is replaced or removed, so that a running handler isn't put back afterwards.
+    is_out_of_memory_handler_replaced: bool,
+    fit_strategy: FitStrategy,
     base_address: *mut u8,
     free_lists_with_unallocated_memory: BitMask,
-    first_in_free_list: [Pointer<FreeChunkHeader>; BIN_CONFIG.bin_count as usize],
+    first_in_free_list: [Link; BIN_CONFIG.bin_count as usize],
     env: E,
 }
 
@@ -574,19 +940,41 @@ unsafe impl<E: Env> Send for Allocator<E> where E: Send {}
 
 impl<E: Env> Drop for Allocator<E> {
     fn drop(&mut self) {
+        // The address space is only allocated on the first allocation.
+        if self.base_address.is_null() {
+            return;
+        }
+
         unsafe {
             self.env.free_address_space(self.base_address);
         }
     }
 }
 
+impl Allocator<crate::SliceEnv> {
+    /// Creates a new allocator which will allocate memory from `length` bytes starting at `pointer`.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid for reads and writes for as long as the allocator is alive.
+    pub unsafe fn from_region(pointer: *mut u8, length: usize) -> Self {
+        Self::new(crate::SliceEnv::new(pointer, length))
+    }
+}
+
 impl<E: Env> Allocator<E> {
     pub const fn new(env: E) -> Self {
         Allocator {
             allocated_space: const { Size::from_bytes_usize(0).unwrap() },
+            used_space: const { Size::from_bytes_usize(0).unwrap() },
+            allocation_count: 0,
+            budget: None,
+            out_of_memory_handler: None,
+            is_out_of_memory_handler_replaced: false,
+            fit_strategy: FitStrategy::FirstInBin,
             base_address: core::ptr::null_mut(),
             free_lists_with_unallocated_memory: BitMask::new(),
-            first_in_free_list: [Pointer::NULL; BIN_CONFIG.bin_count as usize],
+            first_in_free_list: [Link::NULL; BIN_CONFIG.bin_count as usize],
             env,
         }
     }
@@ -633,13 +1021,13 @@ impl<E: Env> Allocator<E> {
         let chunk_header = FreeChunkHeader {
             prev_chunk_size: Size(0),
             size: ChunkSize::new_unallocated(total_space),
-            next_in_list: Pointer::NULL,
-            prev_in_list: Pointer::NULL,
+            next_in_list: Link::NULL,
+            prev_in_list: Link::NULL,
         };
 
         unsafe {
             chunk.write(chunk_header);
-            *get_mut_unchecked(&mut self.first_in_free_list, bin.index()) = Pointer::from_pointer(chunk);
+            *get_mut_unchecked(&mut self.first_in_free_list, bin.index()) = Link::new(Pointer::from_pointer(chunk), base_address);
         }
 
         self.paranoid_check_chunk(Pointer::from_pointer(chunk).cast());
@@ -665,19 +1053,22 @@ impl<E: Env> Allocator<E> {
     #[inline(always)]
     fn unregister_free_space_first_chunk(&mut self, chunk: Pointer<FreeChunkHeader>, bin: BitIndex) {
         self.paranoid_check_access(chunk);
-        paranoid_assert_eq!(self.first_in_free_list[bin.index()], chunk);
+        paranoid_assert_eq!(self.first_in_free_list[bin.index()], Link::new(chunk, self.base_address));
 
         unsafe {
             paranoid_assert!(!chunk.get_unchecked(self.base_address).size.is_allocated());
             paranoid_assert!(chunk.get_unchecked(self.base_address).prev_in_list.is_null());
             let next_in_list = chunk.get_unchecked(self.base_address).next_in_list;
-            paranoid_assert!(next_in_list != chunk);
+            paranoid_assert!(next_in_list != Link::new(chunk, self.base_address));
 
             *get_mut_unchecked(&mut self.first_in_free_list, bin.index()) = next_in_list;
             if next_in_list.is_null() {
                 self.free_lists_with_unallocated_memory.unset(bin);
             } else {
-                next_in_list.get_mut_unchecked(self.base_address).prev_in_list = Pointer::NULL;
+                next_in_list
+                    .get(self.base_address)
+                    .get_mut_unchecked(self.base_address)
+                    .prev_in_list = Link::NULL;
             }
         }
     }
@@ -686,21 +1077,28 @@ impl<E: Env> Allocator<E> {
     fn unregister_free_space(&mut self, chunk: Pointer<FreeChunkHeader>, bin: BitIndex) {
         self.paranoid_check_access(chunk);
 
-        if unsafe { *get_unchecked(&self.first_in_free_list, bin.index()) } == chunk {
+        let link = Link::new(chunk, self.base_address);
+        if unsafe { *get_unchecked(&self.first_in_free_list, bin.index()) } == link {
             self.unregister_free_space_first_chunk(chunk, bin);
         } else {
             let chunk_ref = unsafe { chunk.get_unchecked(self.base_address) };
             paranoid_assert!(!chunk_ref.size.is_allocated());
             let next_in_list = chunk_ref.next_in_list;
             let prev_in_list = chunk_ref.prev_in_list;
-            paranoid_assert!(next_in_list != chunk);
-            paranoid_assert!(prev_in_list != chunk);
+            paranoid_assert!(next_in_list != link);
+            paranoid_assert!(prev_in_list != link);
             paranoid_assert!(!prev_in_list.is_null());
 
             unsafe {
-                prev_in_list.get_mut_unchecked(self.base_address).next_in_list = next_in_list;
+                prev_in_list
+                    .get(self.base_address)
+                    .get_mut_unchecked(self.base_address)
+                    .next_in_list = next_in_list;
                 if !next_in_list.is_null() {
-                    next_in_list.get_mut_unchecked(self.base_address).prev_in_list = prev_in_list;
+                    next_in_list
+                        .get(self.base_address)
+                        .get_mut_unchecked(self.base_address)
+                        .prev_in_list = prev_in_list;
                 }
             }
         }
@@ -715,20 +1113,24 @@ impl<E: Env> Allocator<E> {
         self.paranoid_check_access(chunk);
 
         let bin = Self::size_to_bin_round_down(size);
+        let link = Link::new(chunk, self.base_address);
         unsafe {
-            let next_in_list = core::mem::replace(get_mut_unchecked(&mut self.first_in_free_list, bin.index()), chunk);
+            let next_in_list = core::mem::replace(get_mut_unchecked(&mut self.first_in_free_list, bin.index()), link);
             chunk.write_no_drop(
                 self.base_address,
                 FreeChunkHeader {
                     prev_chunk_size,
                     size: ChunkSize::new_unallocated(size),
                     next_in_list,
-                    prev_in_list: Pointer::NULL,
+                    prev_in_list: Link::NULL,
                 },
             );
 
             if !next_in_list.is_null() {
-                next_in_list.get_mut_unchecked(self.base_address).prev_in_list = chunk;
+                next_in_list
+                    .get(self.base_address)
+                    .get_mut_unchecked(self.base_address)
+                    .prev_in_list = link;
             }
         }
 
@@ -737,7 +1139,7 @@ impl<E: Env> Allocator<E> {
     }
 
     #[inline(always)]
-    fn register_allocation(&mut self, chunk: Pointer<ChunkHeader>, prev_chunk_size: Size, size: Size) {
+    fn register_allocation(&mut self, chunk: Pointer<ChunkHeader>, prev_chunk_size: Size, size: Size, unused_bytes: u32) {
         self.paranoid_check_access(chunk);
 
         unsafe {
@@ -746,153 +1148,928 @@ impl<E: Env> Allocator<E> {
                 ChunkHeader {
                     prev_chunk_size,
                     size: ChunkSize::new_allocated(size),
+                    unused_bytes,
                 },
             );
         }
     }
 
-    /// Allocates zeroed memory.
-    #[inline(always)]
-    pub fn alloc_zeroed(&mut self, align: Size, requested_size: Size) -> Option<NonNull<u8>> {
-        self.alloc_impl(align, requested_size, true)
-    }
-
-    /// Allocates memory.
+    /// Grows the allocated `chunk` over the free chunks right after it, which must have been already unregistered.
+    ///
+    /// The chunk which follows the merged one is updated first, so that the chunks can be walked after every store.
     #[inline(always)]
-    pub fn alloc(&mut self, align: Size, requested_size: Size) -> Option<NonNull<u8>> {
-        self.alloc_impl(align, requested_size, false)
-    }
-
-    fn alloc_impl(&mut self, align: Size, requested_size: Size, is_calloc: bool) -> Option<NonNull<u8>> {
-        if align.0 == 0 || !align.0.is_power_of_two() {
-            return None;
+    fn merge_into_allocation(&mut self, chunk: Pointer<ChunkHeader>, size: Size) {
+        let final_chunk = chunk.unchecked_add(size);
+        if final_chunk.cast() < Pointer::from_pointer(self.base_address).unchecked_add(self.env.total_space()) {
+            self.paranoid_check_access(final_chunk);
+            unsafe {
+                final_chunk.get_mut_unchecked(self.base_address).prev_chunk_size = size;
+            }
         }
 
-        if !self.initialize() {
-            return None;
+        header_store_barrier();
+        unsafe {
+            chunk.get_mut_unchecked(self.base_address).size = ChunkSize::new_allocated(size);
         }
 
-        let min_size = requested_size.checked_add(HEADER_SIZE)?.checked_add(align.unchecked_sub(Size(1)))?;
-        if min_size.0 > MAX_ALLOCATION_SIZE.0 {
-            return None;
-        }
+        header_store_barrier();
+    }
 
-        // Find a bin with enough free space.
-        //
-        // First calculate the minimum bin to fit this allocation; round up in case the size doesn't match the bin size exactly.
-        // If this doesn't work then try rounding down and see if maybe we can find an oversized region in the previous bin.
-        let min_size_round_up = Self::size_to_bin_round_up(min_size);
-        let min_size_round_down = Self::size_to_bin_round_down(min_size);
-        let mut bin = self.free_lists_with_unallocated_memory.find_first(min_size_round_up);
+    /// Initializes the heap right away instead of on the first allocation.
+    #[cfg(any(feature = "shared_heap", feature = "persistent_heap"))]
+    pub(crate) fn initialize_now(&mut self) -> bool {
+        self.initialize()
+    }
 
-        if bin.is_none() {
-            bin = self.free_lists_with_unallocated_memory.find_first(min_size_round_down);
-        }
+    /// Takes over an existing heap at `base_address` and rebuilds the free lists by walking over its chunks.
+    ///
+    /// Adjacent free chunks are merged. Returns `false` if the chunks are inconsistent.
+    ///
+    /// If an operation was interrupted the chunk right after it can still have the combined size of several chunks
+    /// before it as its `prev_chunk_size`; at most one such chunk is accepted.
+    #[cfg(feature = "persistent_heap")]
+    pub(crate) unsafe fn recover(&mut self, base_address: *mut u8, allocated_space: Size) -> bool {
+        let total_space = self.env.total_space();
+        self.base_address = base_address;
+        self.allocated_space = core::cmp::min(allocated_space, total_space);
+        self.used_space = Size(0);
+        self.allocation_count = 0;
+        self.free_lists_with_unallocated_memory = BitMask::new();
+        self.first_in_free_list = [Link::NULL; BIN_CONFIG.bin_count as usize];
+
+        let base_address = Pointer::from_pointer_mut(self.base_address);
+        let mut offset = Size(0);
+        let mut prev_chunk_size = Size(0);
+        let mut walked_prev_chunk_size = Size(0);
+        let mut is_stale_allowed = true;
+        let mut free_run: Option<(Size, Size)> = None;
+        while offset < total_space {
+            if offset.unchecked_add(HEADER_SIZE) > self.allocated_space {
+                return false;
+            }
 
-        let bin = bin?;
+            let chunk = base_address.unchecked_add(offset).cast::<ChunkHeader>();
+            let header = *chunk.get_unchecked(self.base_address);
+            let size = header.size;
+            if size.size().is_empty() || size.size() > total_space.unchecked_sub(offset) {
+                return false;
+            }
 
-        let chunk = unsafe { *get_unchecked(&self.first_in_free_list, bin.index()) };
-        self.paranoid_check_chunk(chunk.cast::<ChunkHeader>());
+            if header.prev_chunk_size != walked_prev_chunk_size {
+                if !is_stale_allowed
+                    || header.prev_chunk_size < walked_prev_chunk_size
+                    || header.prev_chunk_size > offset
+                    || !self.is_chunk_start(offset.unchecked_sub(header.prev_chunk_size))
+                {
+                    return false;
+                }
 
-        let chunk_size = unsafe { chunk.get_unchecked(self.base_address).size };
-        paranoid_assert!(!chunk_size.is_allocated());
+                is_stale_allowed = false;
+            }
 
-        let chunk_size = chunk_size.size();
-        paranoid_assert_eq!(Self::size_to_bin_round_down(chunk_size), bin);
+            if size.is_allocated() {
+                if offset.unchecked_add(size.size()) > self.allocated_space {
+                    return false;
+                }
 
-        if chunk_size < min_size {
-            return None;
-        }
+                if let Some((start, prev_run_chunk_size)) = free_run.take() {
+                    // Like in `free`, the merged chunk is only written after the chunk following it points to its start.
+                    prev_chunk_size = offset.unchecked_sub(start);
+                    chunk.get_mut_unchecked(self.base_address).prev_chunk_size = prev_chunk_size;
+                    header_store_barrier();
+                    self.register_free_space(base_address.unchecked_add(start).cast(), prev_run_chunk_size, prev_chunk_size);
+                }
 
-        let chunk_offset = Size::from_pointer_and_base_unchecked(chunk, Pointer::from_pointer_mut(self.base_address));
-        let data_offset = Size(align_offset(chunk_offset.0 + HEADER_SIZE.0, align.0, self.base_address.addr()));
-        let header_offset = data_offset.unchecked_sub(HEADER_SIZE);
-        let allocation_chunk = Pointer::from_pointer_mut(self.base_address)
-            .unchecked_add(header_offset)
-            .cast::<ChunkHeader>();
+                chunk.get_mut_unchecked(self.base_address).prev_chunk_size = prev_chunk_size;
+                self.used_space = self.used_space.unchecked_add(size.size().unchecked_sub(HEADER_SIZE));
+                self.allocation_count += 1;
+                prev_chunk_size = size.size();
+            } else if free_run.is_none() {
+                free_run = Some((offset, prev_chunk_size));
+            }
 
-        paranoid_assert!(header_offset >= chunk_offset);
+            walked_prev_chunk_size = size.size();
+            offset = offset.unchecked_add(size.size());
+        }
 
-        let free_space_lhs = header_offset.unchecked_sub(chunk_offset);
-        let free_space_rhs = chunk_size
-            .unchecked_sub(requested_size)
-            .unchecked_sub(free_space_lhs)
-            .unchecked_sub(HEADER_SIZE);
+        if let Some((start, prev_run_chunk_size)) = free_run {
+            self.register_free_space(
+                base_address.unchecked_add(start).cast(),
+                prev_run_chunk_size,
+                total_space.unchecked_sub(start),
+            );
+        }
 
-        let mut end_offset = data_offset.unchecked_add(requested_size);
-        if !free_space_rhs.is_empty() {
-            end_offset = end_offset.unchecked_add(FREE_CHUNK_HEADER_SIZE);
+        true
+    }
+
+    /// Checks whether a chunk starts at `target` by walking the chunks from the start of the heap.
+    #[cfg(feature = "persistent_heap")]
+    unsafe fn is_chunk_start(&self, target: Size) -> bool {
+        let base_address = Pointer::from_pointer_mut(self.base_address);
+        let mut offset = Size(0);
+        while offset < target {
+            let chunk = base_address.unchecked_add(offset).cast::<ChunkHeader>();
+            offset = offset.unchecked_add(chunk.get_unchecked(self.base_address).size.size());
         }
 
-        let zero_memory = self.allocated_space > data_offset && is_calloc;
-        if self.allocated_space < end_offset {
-            if !unsafe { self.env.expand_memory_until(self.base_address, end_offset) } {
-                return None;
-            }
+        offset == target
+    }
 
-            self.allocated_space = end_offset;
+    /// Moves an already initialized heap to `base_address`; does nothing if the heap wasn't initialized yet.
+    ///
+    /// All of the pointers into the heap must be translated using their offset from [`Allocator::base_address`](Allocator::base_address).
+    /// The new address is passed to the [`Env`] in all subsequent calls.
+    ///
+    /// # Safety
+    ///
+    /// The first [`Allocator::allocated_space`](Allocator::allocated_space) bytes of the heap must have been copied to `base_address`,
+    /// which must be aligned to at least 32 bytes; the rest of the new region must meet the same requirements as the original one.
+    #[cfg(feature = "relocatable")]
+    pub unsafe fn relocate(&mut self, base_address: *mut u8) {
+        paranoid_assert_eq!(base_address.addr() % 32, 0);
+        if !self.base_address.is_null() {
+            self.base_address = base_address;
         }
+    }
 
-        unsafe {
-            let mut prev_chunk_size = chunk.get_unchecked(self.base_address).prev_chunk_size;
-            self.unregister_free_space_first_chunk(chunk, bin);
+    /// Returns a reference to the underlying environment.
+    #[inline]
+    pub fn env(&self) -> &E {
+        &self.env
+    }
 
-            prev_chunk_size = self.register_free_space(chunk, prev_chunk_size, free_space_lhs);
-            self.register_allocation(allocation_chunk, prev_chunk_size, requested_size.unchecked_add(HEADER_SIZE));
+    /// Returns a mutable reference to the underlying environment.
+    #[inline]
+    pub fn env_mut(&mut self) -> &mut E {
+        &mut self.env
+    }
 
-            prev_chunk_size = requested_size.unchecked_add(HEADER_SIZE);
-            let next_chunk = allocation_chunk
-                .unchecked_add(HEADER_SIZE)
-                .unchecked_add(requested_size)
-                .cast::<FreeChunkHeader>();
+    /// Returns the start of the heap, or null if it wasn't initialized yet.
+    #[inline]
+    pub fn base_address(&self) -> *mut u8 {
+        self.base_address
+    }
 
-            prev_chunk_size = self.register_free_space(next_chunk, prev_chunk_size, free_space_rhs);
-            let final_chunk = next_chunk.unchecked_add(free_space_rhs);
+    /// Returns how much of the address space was made accessible through [`Env::expand_memory_until`](Env::expand_memory_until) so far.
+    #[inline]
+    pub fn allocated_space(&self) -> Size {
+        self.allocated_space
+    }
 
-            if final_chunk.cast() < Pointer::from_pointer(self.base_address).unchecked_add(self.env.total_space()) {
-                self.paranoid_check_access(final_chunk);
-                final_chunk.get_mut_unchecked(self.base_address).prev_chunk_size = prev_chunk_size;
-            }
+    /// Returns the total usable space of all of the live allocations.
+    #[inline]
+    pub fn used_space(&self) -> Size {
+        self.used_space
+    }
 
-            self.paranoid_check_chunk(allocation_chunk);
-            self.paranoid_check_chunk(allocation_chunk.unchecked_sub(free_space_lhs));
-            self.paranoid_check_chunk(allocation_chunk.unchecked_add(HEADER_SIZE).unchecked_add(requested_size));
-            paranoid_assert_eq!(
-                allocation_chunk.get_unchecked(self.base_address).size.size(),
-                requested_size.unchecked_add(HEADER_SIZE)
-            );
-        }
+    /// Returns the space which is counted against the budget, which is the [`used_space`](Allocator::used_space)
+    /// plus the header of every live allocation.
+    #[inline]
+    pub fn charged_space(&self) -> Size {
+        self.used_space.unchecked_add(Size(HEADER_SIZE.0 * self.allocation_count))
+    }
 
-        let data: Pointer<u8> = allocation_chunk.unchecked_add(HEADER_SIZE).cast();
-        paranoid_assert_eq!(data.address() % align.bytes() as Address, 0);
+    /// Sets the maximum total space taken by all of the live allocations, including their headers.
+    ///
+    /// Allocations which would exceed the budget fail with [`AllocError::BudgetExceeded`]. Lowering the budget
+    /// below the current [`charged_space`](Allocator::charged_space) doesn't affect the existing allocations.
+    pub fn set_budget(&mut self, budget: Option<Size>) {
+        self.budget = budget;
+    }
 
-        let output = data.raw_pointer_mut(self.base_address);
-        self.paranoid_check_chunk(Pointer::from_pointer(output).unchecked_sub(HEADER_SIZE).cast::<ChunkHeader>());
+    /// Returns the current budget, if any.
+    #[inline]
+    pub fn budget(&self) -> Option<Size> {
+        self.budget
+    }
 
-        if zero_memory {
-            unsafe {
-                output.write_bytes(0, requested_size.bytes() as usize);
+    /// Returns how much more space can be allocated before the budget is exceeded, or `None` if there is no budget.
+    #[inline]
+    pub fn remaining_budget(&self) -> Option<Size> {
+        self.budget.map(|budget| {
+            let charged_space = self.charged_space();
+            if budget > charged_space {
+                budget.unchecked_sub(charged_space)
+            } else {
+                Size(0)
             }
+        })
+    }
+
+    /// Checks whether `extra_space` can be charged once `released_space` of the already charged space is released.
+    #[inline(always)]
+    fn is_within_budget(&self, extra_space: Size, released_space: Size) -> bool {
+        match self.budget {
+            None => true,
+            Some(budget) => self
+                .charged_space()
+                .unchecked_sub(released_space)
+                .checked_add(extra_space)
+                .is_some_and(|charged_space| charged_space <= budget),
         }
+    }
 
-        Some(unsafe { NonNull::new_unchecked(output) })
+    /// Sets the callback which is called when an allocation fails due to running out of memory.
+    ///
+    /// This can also be called from within the handler, e.g. to remove it.
+    pub fn set_out_of_memory_handler(&mut self, handler: Option<OutOfMemoryHandler<E>>) {
+        self.out_of_memory_handler = handler;
+        self.is_out_of_memory_handler_replaced = true;
     }
 
-    #[cfg(any(test, feature = "paranoid"))]
-    #[inline(never)]
-    #[track_caller]
-    fn paranoid_check_access<T>(&self, pointer: Pointer<T>) {
-        paranoid_assert!(!pointer.is_null());
-        paranoid_assert!(
-            pointer
-                .address()
-                .wrapping_sub(self.base_address.addr() as Address)
-                .wrapping_add(core::mem::size_of::<T>() as Address)
-                <= Address::from(self.allocated_space.bytes())
-        );
+    /// Sets how a free chunk is picked for a new allocation.
+    pub fn set_fit_strategy(&mut self, fit_strategy: FitStrategy) {
+        self.fit_strategy = fit_strategy;
     }
 
-    #[cfg(not(any(test, feature = "paranoid")))]
+    /// Returns how a free chunk is picked for a new allocation.
+    #[inline]
+    pub fn fit_strategy(&self) -> FitStrategy {
+        self.fit_strategy
+    }
+
+    /// Returns an iterator over all of the bins which contain free chunks, along with their statistics.
+    pub fn free_bins(&self) -> FreeBins<E> {
+        FreeBins {
+            allocator: self,
+            next_index: 0,
+        }
+    }
+
+    /// Walks the whole heap and calculates the fragmentation metrics.
+    pub fn fragmentation(&self) -> Fragmentation {
+        let mut output = Fragmentation::default();
+        if self.base_address.is_null() {
+            return output;
+        }
+
+        let base_address = Pointer::from_pointer_mut(self.base_address);
+        let end_of_address_space = base_address.unchecked_add(self.env.total_space());
+        let end_of_allocated_space = base_address.unchecked_add(self.allocated_space);
+        let mut chunk = base_address.cast::<ChunkHeader>();
+        while chunk.cast() < end_of_address_space {
+            self.paranoid_check_chunk(chunk);
+
+            let size = unsafe { chunk.get_unchecked(self.base_address).size };
+            let next_chunk = chunk.unchecked_add(size.size());
+            let bytes = u64::from(size.size().bytes());
+            if size.is_allocated() {
+                let usable_bytes = bytes - u64::from(HEADER_SIZE.bytes());
+                let unused_bytes = u64::from(unsafe { chunk.get_unchecked(self.base_address).unused_bytes });
+                output.allocated_chunks += 1;
+                output.allocated_space += usable_bytes;
+                output.header_overhead += u64::from(HEADER_SIZE.bytes());
+                output.rounding_waste += core::cmp::min(unused_bytes, usable_bytes);
+            } else {
+                output.free_chunks += 1;
+                output.free_space += bytes;
+                if size.size() <= FREE_CHUNK_HEADER_SIZE {
+                    output.padding_waste += bytes;
+                }
+                output.largest_free_chunk = core::cmp::max(output.largest_free_chunk, bytes);
+                if next_chunk.cast() > end_of_allocated_space {
+                    output.uncommitted_free_space +=
+                        (next_chunk.address() - core::cmp::max(chunk.cast(), end_of_allocated_space).address()) as u64;
+                }
+            }
+
+            chunk = next_chunk;
+        }
+
+        output
+    }
+
+    /// Returns whether `pointer` points into this allocator's address space.
+    pub fn owns(&self, pointer: *const u8) -> bool {
+        !self.base_address.is_null() && pointer.addr().wrapping_sub(self.base_address.addr()) < self.env.total_space().bytes() as usize
+    }
+
+    /// Returns the details of the chunk which contains `pointer`, which can point anywhere inside of the chunk's usable memory.
+    ///
+    /// Returns `None` if the pointer doesn't belong to this allocator or points into a chunk header. This walks the heap
+    /// from the start, so it takes time proportional to the number of chunks before the pointer.
+    pub fn allocation_info(&self, pointer: *const u8) -> Option<AllocInfo> {
+        if !self.owns(pointer) {
+            return None;
+        }
+
+        let offset = pointer.addr() - self.base_address.addr();
+        let base_address = Pointer::from_pointer_mut(self.base_address);
+        let end_of_address_space = base_address.unchecked_add(self.env.total_space());
+        let mut chunk = base_address.cast::<ChunkHeader>();
+        while chunk.cast() < end_of_address_space {
+            self.paranoid_check_chunk(chunk);
+
+            let size = unsafe { chunk.get_unchecked(self.base_address).size };
+            let chunk_offset = Size::from_pointer_and_base_unchecked(chunk, base_address).bytes() as usize;
+            let data_offset = chunk_offset + HEADER_SIZE.bytes() as usize;
+            let end_offset = chunk_offset + size.size().bytes() as usize;
+            if offset < data_offset {
+                return None;
+            }
+
+            // A pointer to an empty allocation is equal to the address of the next chunk.
+            if offset < end_offset || offset == data_offset {
+                let start = chunk.unchecked_add(HEADER_SIZE).cast::<u8>().raw_pointer_mut(self.base_address);
+                return Some(AllocInfo {
+                    start: unsafe { NonNull::new_unchecked(start) },
+                    usable_size: end_offset - data_offset,
+                    is_allocated: size.is_allocated(),
+                    offset: offset - data_offset,
+                });
+            }
+
+            chunk = chunk.unchecked_add(size.size());
+        }
+
+        None
+    }
+
+    /// Allocates zeroed memory.
+    #[inline(always)]
+    pub fn alloc_zeroed(&mut self, align: Size, requested_size: Size) -> Option<NonNull<u8>> {
+        self.try_alloc_zeroed(align, requested_size).ok()
+    }
+
+    /// Allocates memory.
+    #[inline(always)]
+    pub fn alloc(&mut self, align: Size, requested_size: Size) -> Option<NonNull<u8>> {
+        self.try_alloc(align, requested_size).ok()
+    }
+
+    /// Allocates memory, returning it together with its whole usable size.
+    ///
+    /// The usable size is read back from the allocation, so it can be bigger than `requested_size`.
+    #[inline(always)]
+    pub fn alloc_sized(&mut self, align: Size, requested_size: Size) -> Option<NonNull<[u8]>> {
+        let pointer = self.alloc(align, requested_size)?;
+        Some(Self::sized(pointer))
+    }
+
+    #[inline(always)]
+    fn sized(pointer: NonNull<u8>) -> NonNull<[u8]> {
+        let usable_size = unsafe { Self::usable_size_impl(pointer) };
+        NonNull::slice_from_raw_parts(pointer, usable_size.bytes() as usize)
+    }
+
+    /// Allocates zeroed memory, returning the reason of the failure if it fails.
+    #[inline(always)]
+    pub fn try_alloc_zeroed(&mut self, align: Size, requested_size: Size) -> Result<NonNull<u8>, AllocError> {
+        let result = self.alloc_with_retry(align, requested_size, true, Size(0));
+
+        #[cfg(feature = "trace")]
+        self.trace_alloc(align, requested_size, true, result.ok());
+
+        result
+    }
+
+    /// Allocates memory, returning the reason of the failure if it fails.
+    #[inline(always)]
+    pub fn try_alloc(&mut self, align: Size, requested_size: Size) -> Result<NonNull<u8>, AllocError> {
+        let result = self.alloc_with_retry(align, requested_size, false, Size(0));
+
+        #[cfg(feature = "trace")]
+        self.trace_alloc(align, requested_size, false, result.ok());
+
+        result
+    }
+
+    /// Allocates up to `out.len()` objects of the same size, returning how many were allocated.
+    ///
+    /// When possible many objects are carved out of a single free chunk at once. Only as many elements
+    /// at the start of `out` as were allocated are initialized.
+    pub fn alloc_batch(&mut self, align: Size, requested_size: Size, out: &mut [MaybeUninit<NonNull<u8>>]) -> usize {
+        let mut count = 0;
+        while let Some(out) = out.get_mut(count..).filter(|out| !out.is_empty()) {
+            // Allocations which need more alignment than the granularity can't be simply placed one after another.
+            let result = if align.0 == 1 {
+                self.alloc_batch_impl(requested_size, out)
+            } else {
+                Err(AllocError::OutOfSpace)
+            };
+
+            if let Ok(allocated) = result {
+                #[cfg(feature = "trace")]
+                for pointer in &out[..allocated] {
+                    self.trace_alloc(align, requested_size, false, Some(unsafe { pointer.assume_init() }));
+                }
+
+                count += allocated;
+                continue;
+            }
+
+            // Fall back to a single allocation, which also gives the out-of-memory handler a chance to run.
+            let result = self.alloc_with_retry(align, requested_size, false, Size(0));
+
+            #[cfg(feature = "trace")]
+            self.trace_alloc(align, requested_size, false, result.ok());
+
+            let Ok(pointer) = result else { break };
+            if let Some(slot) = out.first_mut() {
+                slot.write(pointer);
+            }
+
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Carves as many objects as possible, up to `out.len()`, out of a single free chunk.
+    fn alloc_batch_impl(&mut self, requested_size: Size, out: &mut [MaybeUninit<NonNull<u8>>]) -> Result<usize, AllocError> {
+        if !self.initialize() {
+            return Err(AllocError::InitializationFailed);
+        }
+
+        let Some(object_size) = requested_size.checked_add(HEADER_SIZE) else {
+            return Err(AllocError::SizeOverflow);
+        };
+
+        if object_size.0 > MAX_ALLOCATION_SIZE.0 {
+            return Err(AllocError::SizeOverflow);
+        }
+
+        let mut max_count = out.len();
+        if let Some(remaining_budget) = self.remaining_budget() {
+            max_count = core::cmp::min(max_count, (remaining_budget.0 / object_size.0) as usize);
+        }
+
+        if max_count == 0 {
+            return Err(AllocError::BudgetExceeded);
+        }
+
+        let Some((chunk, bin)) = self.find_free_chunk(object_size) else {
+            return Err(AllocError::OutOfSpace);
+        };
+
+        self.paranoid_check_chunk(chunk.cast::<ChunkHeader>());
+
+        let chunk_header = unsafe { chunk.get_unchecked(self.base_address) };
+        paranoid_assert!(!chunk_header.size.is_allocated());
+
+        let chunk_size = chunk_header.size.size();
+        let prev_chunk_size = chunk_header.prev_chunk_size;
+        paranoid_assert!(chunk_size >= object_size);
+
+        let count = core::cmp::min(max_count, (chunk_size.0 / object_size.0) as usize);
+        let used_size = Size(object_size.0 * count as SizeT);
+        let free_space = chunk_size.unchecked_sub(used_size);
+
+        let chunk_offset = Size::from_pointer_and_base_unchecked(chunk, Pointer::from_pointer_mut(self.base_address));
+        let mut end_offset = chunk_offset.unchecked_add(used_size);
+        if !free_space.is_empty() {
+            end_offset = end_offset.unchecked_add(FREE_CHUNK_HEADER_SIZE);
+        }
+
+        if self.allocated_space < end_offset {
+            if !unsafe { self.env.expand_memory_until(self.base_address, end_offset) } {
+                return Err(AllocError::ExpansionRefused);
+            }
+
+            self.allocated_space = end_offset;
+        }
+
+        self.unregister_free_space(chunk, bin);
+
+        // The headers are written from the end, so that the chunk is only split once all of them are in place.
+        let next_chunk = chunk.unchecked_add(used_size);
+        let next_chunk_size = self.register_free_space(next_chunk, object_size, free_space);
+        header_store_barrier();
+
+        let mut allocation_chunk = next_chunk.cast::<ChunkHeader>();
+        for (index, slot) in out[..count].iter_mut().enumerate().rev() {
+            allocation_chunk = allocation_chunk.unchecked_sub(object_size);
+            let chunk_prev_chunk_size = if index == 0 { prev_chunk_size } else { object_size };
+            self.register_allocation(allocation_chunk, chunk_prev_chunk_size, object_size, 0);
+            header_store_barrier();
+
+            let data: Pointer<u8> = allocation_chunk.unchecked_add(HEADER_SIZE).cast();
+            slot.write(unsafe { NonNull::new_unchecked(data.raw_pointer_mut(self.base_address)) });
+        }
+
+        let final_chunk = next_chunk.unchecked_add(free_space);
+        if final_chunk.cast() < Pointer::from_pointer(self.base_address).unchecked_add(self.env.total_space()) {
+            self.paranoid_check_access(final_chunk);
+            unsafe {
+                final_chunk.get_mut_unchecked(self.base_address).prev_chunk_size = next_chunk_size;
+            }
+        }
+
+        self.paranoid_check_chunk(chunk.cast());
+        self.paranoid_check_chunk(next_chunk.cast());
+        self.paranoid_check_chunk(final_chunk.cast());
+
+        self.used_space = self.used_space.unchecked_add(Size(requested_size.0 * count as SizeT));
+        self.allocation_count += count as SizeT;
+        Ok(count)
+    }
+
+    #[cfg(feature = "trace")]
+    #[inline]
+    fn trace_handle(&self, pointer: NonNull<u8>) -> u32 {
+        Size::from_pointer_and_base_unchecked(
+            Pointer::<u8>::from_pointer(pointer.as_ptr()),
+            Pointer::from_pointer_mut(self.base_address),
+        )
+        .0
+    }
+
+    #[cfg(feature = "trace")]
+    #[inline(never)]
+    fn trace_alloc(&mut self, align: Size, size: Size, zeroed: bool, pointer: Option<NonNull<u8>>) {
+        let result = pointer.map(|pointer| self.trace_handle(pointer));
+        self.env.trace(crate::TraceEvent::Alloc {
+            align,
+            size,
+            zeroed,
+            result,
+        });
+    }
+
+    #[inline(always)]
+    /// Allocates memory, calling the out-of-memory handler on failure.
+    ///
+    /// The `released_space` is the part of the budget which is going to be released once this allocation succeeds.
+    fn alloc_with_retry(
+        &mut self,
+        align: Size,
+        requested_size: Size,
+        is_calloc: bool,
+        released_space: Size,
+    ) -> Result<NonNull<u8>, AllocError> {
+        match self.alloc_impl(align, requested_size, is_calloc, released_space) {
+            Err(error) if error.is_out_of_memory() && self.out_of_memory_handler.is_some() => {
+                self.handle_out_of_memory(align, requested_size, is_calloc, released_space, error)
+            }
+            result => result,
+        }
+    }
+
+    #[inline(never)]
+    #[cold]
+    fn handle_out_of_memory(
+        &mut self,
+        align: Size,
+        size: Size,
+        zeroed: bool,
+        released_space: Size,
+        error: AllocError,
+    ) -> Result<NonNull<u8>, AllocError> {
+        // Take the handler out so that any allocation done by the handler itself won't recursively call it.
+        let Some(handler) = self.out_of_memory_handler.take() else {
+            return Err(error);
+        };
+
+        let info = OutOfMemory {
+            align,
+            size,
+            zeroed,
+            error,
+            used_space: self.used_space,
+            allocated_space: self.allocated_space,
+            total_space: self.env.total_space(),
+        };
+
+        self.is_out_of_memory_handler_replaced = false;
+        let should_retry = handler(self, &info);
+        if !self.is_out_of_memory_handler_replaced {
+            self.out_of_memory_handler = Some(handler);
+        }
+
+        if !should_retry {
+            return Err(error);
+        }
+
+        self.alloc_impl(align, size, zeroed, released_space)
+    }
+
+    fn alloc_impl(&mut self, align: Size, requested_size: Size, is_calloc: bool, released_space: Size) -> Result<NonNull<u8>, AllocError> {
+        if align.0 == 0 || !align.0.is_power_of_two() {
+            return Err(AllocError::InvalidAlignment);
+        }
+
+        if !self.initialize() {
+            return Err(AllocError::InitializationFailed);
+        }
+
+        let is_large_alignment = align >= LARGE_ALIGNMENT;
+        let Some(min_size) = requested_size.checked_add(HEADER_SIZE).and_then(|size| {
+            if is_large_alignment {
+                Some(size)
+            } else {
+                size.checked_add(align.unchecked_sub(Size(1)))
+            }
+        }) else {
+            return Err(AllocError::SizeOverflow);
+        };
+
+        if min_size.0 > MAX_ALLOCATION_SIZE.0 {
+            return Err(AllocError::SizeOverflow);
+        }
+
+        if !self.is_within_budget(requested_size.unchecked_add(HEADER_SIZE), released_space) {
+            return Err(AllocError::BudgetExceeded);
+        }
+
+        let found = if is_large_alignment {
+            self.find_aligned_free_chunk(align, requested_size)
+        } else {
+            self.find_free_chunk(min_size)
+        };
+
+        let Some((chunk, bin)) = found else {
+            return Err(AllocError::OutOfSpace);
+        };
+
+        self.paranoid_check_chunk(chunk.cast::<ChunkHeader>());
+
+        let chunk_size = unsafe { chunk.get_unchecked(self.base_address).size };
+        paranoid_assert!(!chunk_size.is_allocated());
+
+        let chunk_size = chunk_size.size();
+        paranoid_assert_eq!(Self::size_to_bin_round_down(chunk_size), bin);
+        paranoid_assert!(chunk_size >= min_size);
+
+        let chunk_offset = Size::from_pointer_and_base_unchecked(chunk, Pointer::from_pointer_mut(self.base_address));
+        let data_offset = Size(align_offset(chunk_offset.0 + HEADER_SIZE.0, align.0, self.base_address.addr()));
+        let header_offset = data_offset.unchecked_sub(HEADER_SIZE);
+        let allocation_chunk = Pointer::from_pointer_mut(self.base_address)
+            .unchecked_add(header_offset)
+            .cast::<ChunkHeader>();
+
+        paranoid_assert!(header_offset >= chunk_offset);
+        paranoid_assert!(data_offset.unchecked_add(requested_size) <= chunk_offset.unchecked_add(chunk_size));
+
+        let free_space_lhs = header_offset.unchecked_sub(chunk_offset);
+        let mut allocation_size = requested_size.unchecked_add(HEADER_SIZE);
+        let mut free_space_rhs = chunk_size.unchecked_sub(allocation_size).unchecked_sub(free_space_lhs);
+
+        // A free chunk after which there's no room for any data would only ever fit an empty allocation, so it's absorbed instead.
+        if free_space_rhs == FREE_CHUNK_HEADER_SIZE && self.is_within_budget(allocation_size.unchecked_add(free_space_rhs), released_space)
+        {
+            allocation_size = allocation_size.unchecked_add(free_space_rhs);
+            free_space_rhs = Size(0);
+        }
+
+        let usable_size = allocation_size.unchecked_sub(HEADER_SIZE);
+        let mut end_offset = data_offset.unchecked_add(usable_size);
+        if !free_space_rhs.is_empty() {
+            end_offset = end_offset.unchecked_add(FREE_CHUNK_HEADER_SIZE);
+        }
+
+        let zero_memory = self.allocated_space > data_offset && is_calloc;
+        if self.allocated_space < end_offset {
+            if !unsafe { self.env.expand_memory_until(self.base_address, end_offset) } {
+                return Err(AllocError::ExpansionRefused);
+            }
+
+            self.allocated_space = end_offset;
+        }
+
+        unsafe {
+            let prev_chunk_size = chunk.get_unchecked(self.base_address).prev_chunk_size;
+            self.unregister_free_space(chunk, bin);
+
+            // The chunk is split from its end, so that it's only shrunk once the headers after it were written.
+            let next_chunk = allocation_chunk.unchecked_add(allocation_size).cast::<FreeChunkHeader>();
+            let next_chunk_size = self.register_free_space(next_chunk, allocation_size, free_space_rhs);
+            header_store_barrier();
+
+            let lhs_chunk_size = if free_space_lhs.is_empty() {
+                prev_chunk_size
+            } else {
+                free_space_lhs
+            };
+            self.register_allocation(
+                allocation_chunk,
+                lhs_chunk_size,
+                allocation_size,
+                usable_size.unchecked_sub(requested_size).bytes(),
+            );
+            header_store_barrier();
+
+            self.register_free_space(chunk, prev_chunk_size, free_space_lhs);
+            header_store_barrier();
+
+            let final_chunk = next_chunk.unchecked_add(free_space_rhs);
+            if final_chunk.cast() < Pointer::from_pointer(self.base_address).unchecked_add(self.env.total_space()) {
+                self.paranoid_check_access(final_chunk);
+                final_chunk.get_mut_unchecked(self.base_address).prev_chunk_size = next_chunk_size;
+            }
+
+            self.paranoid_check_chunk(allocation_chunk);
+            self.paranoid_check_chunk(allocation_chunk.unchecked_sub(free_space_lhs));
+            self.paranoid_check_chunk(allocation_chunk.unchecked_add(allocation_size));
+            paranoid_assert_eq!(allocation_chunk.get_unchecked(self.base_address).size.size(), allocation_size);
+        }
+
+        let data: Pointer<u8> = allocation_chunk.unchecked_add(HEADER_SIZE).cast();
+        paranoid_assert_eq!(data.address() % align.bytes() as Address, 0);
+
+        let output = data.raw_pointer_mut(self.base_address);
+        self.paranoid_check_chunk(Pointer::from_pointer(output).unchecked_sub(HEADER_SIZE).cast::<ChunkHeader>());
+
+        if zero_memory {
+            unsafe {
+                output.write_bytes(0, usable_size.bytes() as usize);
+            }
+        }
+
+        self.used_space = self.used_space.unchecked_add(usable_size);
+        self.allocation_count += 1;
+        Ok(unsafe { NonNull::new_unchecked(output) })
+    }
+
+    /// Finds a free chunk which is at least `min_size` big according to the current [`FitStrategy`].
+    #[inline(always)]
+    fn find_free_chunk(&self, min_size: Size) -> Option<(Pointer<FreeChunkHeader>, BitIndex)> {
+        // First calculate the minimum bin to fit this allocation; round up in case the size doesn't match the bin size exactly.
+        // If this doesn't work then try rounding down and see if maybe we can find an oversized region in the previous bin.
+        let min_size_round_up = Self::size_to_bin_round_up(min_size);
+        let min_size_round_down = Self::size_to_bin_round_down(min_size);
+        match self.fit_strategy {
+            FitStrategy::FirstInBin => {
+                let bin = self
+                    .free_lists_with_unallocated_memory
+                    .find_first(min_size_round_up)
+                    .or_else(|| self.free_lists_with_unallocated_memory.find_first(min_size_round_down))?;
+
+                let chunk = unsafe { *get_unchecked(&self.first_in_free_list, bin.index()) }.get(self.base_address);
+                if unsafe { chunk.get_unchecked(self.base_address).size.size() } < min_size {
+                    return None;
+                }
+
+                Some((chunk, bin))
+            }
+            FitStrategy::BoundedSearch(limit) => {
+                if let Some(bin) = self.free_lists_with_unallocated_memory.find_first(min_size_round_up) {
+                    let chunk = unsafe { *get_unchecked(&self.first_in_free_list, bin.index()) }.get(self.base_address);
+                    return Some((chunk, bin));
+                }
+
+                let chunk = self.find_smallest_chunk_in_bin(min_size_round_down, min_size, limit)?;
+                Some((chunk, min_size_round_down))
+            }
+            FitStrategy::BestFit => {
+                // Every chunk in the bins above the rounded down one fits, so only look there if nothing in that bin does.
+                if let Some(chunk) = self.find_smallest_chunk_in_bin(min_size_round_down, min_size, u32::MAX) {
+                    return Some((chunk, min_size_round_down));
+                }
+
+                let bin = self.free_lists_with_unallocated_memory.find_first(min_size_round_up)?;
+                let chunk = self.find_smallest_chunk_in_bin(bin, min_size, u32::MAX)?;
+                Some((chunk, bin))
+            }
+            FitStrategy::LowestAddress => {
+                let mut best = None;
+                if min_size_round_down != min_size_round_up {
+                    best = self
+                        .find_lowest_chunk_in_bin(min_size_round_down, min_size)
+                        .map(|chunk| (chunk, min_size_round_down));
+                }
+
+                let mut next_bin = self.free_lists_with_unallocated_memory.find_first(min_size_round_up);
+                while let Some(bin) = next_bin {
+                    if let Some(chunk) = self.find_lowest_chunk_in_bin(bin, min_size) {
+                        let is_better = match best {
+                            None => true,
+                            Some((best_chunk, _)) => chunk.address() < best_chunk.address(),
+                        };
+
+                        if is_better {
+                            best = Some((chunk, bin));
+                        }
+                    }
+
+                    if bin.index + 1 >= BIN_CONFIG.bin_count {
+                        break;
+                    }
+
+                    next_bin = self.free_lists_with_unallocated_memory.find_first(BitMask::index(bin.index + 1));
+                }
+
+                best
+            }
+        }
+    }
+
+    /// Finds the first free chunk which has enough space for `requested_size` bytes at an address aligned to `align`.
+    ///
+    /// This ignores the [`FitStrategy`] and can walk the free lists of every bin which can fit the allocation.
+    #[inline(never)]
+    fn find_aligned_free_chunk(&self, align: Size, requested_size: Size) -> Option<(Pointer<FreeChunkHeader>, BitIndex)> {
+        let min_size = requested_size.unchecked_add(HEADER_SIZE);
+        let mut next_bin = self
+            .free_lists_with_unallocated_memory
+            .find_first(Self::size_to_bin_round_down(min_size));
+
+        while let Some(bin) = next_bin {
+            let mut link = unsafe { *get_unchecked(&self.first_in_free_list, bin.index()) };
+            while !link.is_null() {
+                let chunk = link.get(self.base_address);
+                let chunk_ref = unsafe { chunk.get_unchecked(self.base_address) };
+                let chunk_size = chunk_ref.size.size();
+                if chunk_size >= min_size {
+                    let chunk_offset = Size::from_pointer_and_base_unchecked(chunk, Pointer::from_pointer_mut(self.base_address));
+                    let data_offset = align_offset(chunk_offset.0 + HEADER_SIZE.0, align.0, self.base_address.addr());
+                    let end_offset = u64::from(data_offset) + u64::from(requested_size.0);
+                    if end_offset <= u64::from(chunk_offset.0) + u64::from(chunk_size.0) {
+                        return Some((chunk, bin));
+                    }
+                }
+
+                link = chunk_ref.next_in_list;
+            }
+
+            if bin.index + 1 >= BIN_CONFIG.bin_count {
+                break;
+            }
+
+            next_bin = self.free_lists_with_unallocated_memory.find_first(BitMask::index(bin.index + 1));
+        }
+
+        None
+    }
+
+    /// Returns the smallest chunk in `bin` which is at least `min_size` big, looking at no more than `limit` chunks.
+    ///
+    /// Out of equally big chunks the one with the lowest address is returned.
+    #[inline(never)]
+    fn find_smallest_chunk_in_bin(&self, bin: BitIndex, min_size: Size, limit: u32) -> Option<Pointer<FreeChunkHeader>> {
+        if !self.free_lists_with_unallocated_memory.is_set(bin) {
+            return None;
+        }
+
+        let mut best: Option<(Pointer<FreeChunkHeader>, Size)> = None;
+        let mut link = unsafe { *get_unchecked(&self.first_in_free_list, bin.index()) };
+        let mut remaining = limit;
+        while !link.is_null() && remaining > 0 {
+            let chunk = link.get(self.base_address);
+            let chunk_ref = unsafe { chunk.get_unchecked(self.base_address) };
+            let size = chunk_ref.size.size();
+            if size >= min_size {
+                let is_better = match best {
+                    None => true,
+                    Some((best_chunk, best_size)) => size < best_size || (size == best_size && chunk.address() < best_chunk.address()),
+                };
+
+                if is_better {
+                    best = Some((chunk, size));
+                }
+            }
+
+            link = chunk_ref.next_in_list;
+            remaining -= 1;
+        }
+
+        best.map(|(chunk, _)| chunk)
+    }
+
+    /// Returns the chunk with the lowest address in `bin` which is at least `min_size` big.
+    #[inline(never)]
+    fn find_lowest_chunk_in_bin(&self, bin: BitIndex, min_size: Size) -> Option<Pointer<FreeChunkHeader>> {
+        if !self.free_lists_with_unallocated_memory.is_set(bin) {
+            return None;
+        }
+
+        let mut best: Option<Pointer<FreeChunkHeader>> = None;
+        let mut link = unsafe { *get_unchecked(&self.first_in_free_list, bin.index()) };
+        while !link.is_null() {
+            let chunk = link.get(self.base_address);
+            let chunk_ref = unsafe { chunk.get_unchecked(self.base_address) };
+            if chunk_ref.size.size() >= min_size {
+                let is_better = match best {
+                    None => true,
+                    Some(best_chunk) => chunk.address() < best_chunk.address(),
+                };
+
+                if is_better {
+                    best = Some(chunk);
+                }
+            }
+
+            link = chunk_ref.next_in_list;
+        }
+
+        best
+    }
+
+    #[cfg(any(test, feature = "paranoid"))]
+    #[inline(never)]
+    #[track_caller]
+    fn paranoid_check_access<T>(&self, pointer: Pointer<T>) {
+        paranoid_assert!(!pointer.is_null());
+        paranoid_assert!(
+            pointer
+                .address()
+                .wrapping_sub(self.base_address.addr() as Address)
+                .wrapping_add(core::mem::size_of::<T>() as Address)
+                <= Address::from(self.allocated_space.bytes())
+        );
+    }
+
+    #[cfg(not(any(test, feature = "paranoid")))]
     #[inline(always)]
     fn paranoid_check_access<T>(&self, _pointer: Pointer<T>) {}
 
@@ -954,11 +2131,25 @@ impl<E: Env> Allocator<E> {
     ///
     /// The `pointer` must have come from [`Allocator::alloc`](Allocator::alloc), and must not have been passed to [`Allocator::free`](Allocator::free) beforehand.
     pub unsafe fn shrink_inplace(&mut self, pointer: NonNull<u8>, new_size: Size) {
+        #[cfg(feature = "trace")]
+        let handle = self.trace_handle(pointer);
+
+        self.shrink_inplace_impl(pointer, new_size);
+
+        #[cfg(feature = "trace")]
+        self.env.trace(crate::TraceEvent::Shrink { handle, size: new_size });
+    }
+
+    unsafe fn shrink_inplace_impl(&mut self, pointer: NonNull<u8>, new_size: Size) {
         if new_size.is_empty() {
-            self.free(pointer);
+            self.free_impl(pointer);
             return;
         }
 
+        self.shrink_chunk(pointer, new_size);
+    }
+
+    unsafe fn shrink_chunk(&mut self, pointer: NonNull<u8>, new_size: Size) {
         let pointer = pointer.as_ptr();
         let new_size = new_size.unchecked_add(HEADER_SIZE);
 
@@ -974,7 +2165,7 @@ impl<E: Env> Allocator<E> {
         }
 
         let mut free_space = current_size.unchecked_sub(new_size);
-        chunk.get_mut_unchecked(self.base_address).size = ChunkSize::new_allocated(new_size);
+        self.used_space = self.used_space.unchecked_sub(free_space);
 
         let end_of_address_space = Pointer::from_pointer(self.base_address).unchecked_add(self.env.total_space());
         {
@@ -986,12 +2177,20 @@ impl<E: Env> Allocator<E> {
                     let next_size = next_size.size();
                     self.unregister_free_space(next_chunk.cast::<FreeChunkHeader>(), Self::size_to_bin_round_down(next_size));
                     free_space = free_space.unchecked_add(next_size);
+                    self.merge_into_allocation(chunk, current_size.unchecked_add(next_size));
                 }
             }
         }
 
+        // The free chunk's header is written before the allocation is shrunk, so that the chunks can be walked after every store.
         let next_chunk = chunk.unchecked_add(new_size);
         self.register_free_space(next_chunk.cast::<FreeChunkHeader>(), new_size, free_space);
+        header_store_barrier();
+
+        let header = chunk.get_mut_unchecked(self.base_address);
+        header.size = ChunkSize::new_allocated(new_size);
+        header.unused_bytes = 0;
+        header_store_barrier();
 
         let final_chunk = next_chunk.unchecked_add(free_space);
         if final_chunk.cast() < end_of_address_space {
@@ -1010,7 +2209,34 @@ impl<E: Env> Allocator<E> {
     ///
     /// The `pointer` must have come from [`Allocator::alloc`](Allocator::alloc), and must not have been passed to [`Allocator::free`](Allocator::free) beforehand.
     pub unsafe fn grow_inplace(&mut self, pointer: NonNull<u8>, new_size: Size) -> Option<Size> {
-        let new_size = new_size.checked_add(HEADER_SIZE)?;
+        self.try_grow_inplace(pointer, new_size).ok()
+    }
+
+    /// Tries to grow the memory allocation to at least the given size, returning the reason of the failure if it fails.
+    ///
+    /// # Safety
+    ///
+    /// The `pointer` must have come from [`Allocator::alloc`](Allocator::alloc), and must not have been passed to [`Allocator::free`](Allocator::free) beforehand.
+    pub unsafe fn try_grow_inplace(&mut self, pointer: NonNull<u8>, new_size: Size) -> Result<Size, AllocError> {
+        let result = self.grow_inplace_impl(pointer, new_size);
+
+        #[cfg(feature = "trace")]
+        {
+            let handle = self.trace_handle(pointer);
+            self.env.trace(crate::TraceEvent::Grow {
+                handle,
+                size: new_size,
+                result: result.ok(),
+            });
+        }
+
+        result
+    }
+
+    unsafe fn grow_inplace_impl(&mut self, pointer: NonNull<u8>, new_size: Size) -> Result<Size, AllocError> {
+        let Some(new_size) = new_size.checked_add(HEADER_SIZE) else {
+            return Err(AllocError::SizeOverflow);
+        };
 
         let pointer = pointer.as_ptr();
         let chunk = Pointer::from_pointer(pointer).unchecked_sub(HEADER_SIZE).cast::<ChunkHeader>();
@@ -1021,25 +2247,29 @@ impl<E: Env> Allocator<E> {
 
         let current_size = current_size.size();
         if current_size >= new_size {
-            return Some(current_size.unchecked_sub(HEADER_SIZE));
+            return Ok(current_size.unchecked_sub(HEADER_SIZE));
+        }
+
+        if !self.is_within_budget(new_size.unchecked_sub(current_size), Size(0)) {
+            return Err(AllocError::BudgetExceeded);
         }
 
         let end_of_address_space = Pointer::from_pointer(self.base_address).unchecked_add(self.env.total_space());
         let old_next_chunk = chunk.unchecked_add(current_size);
         if old_next_chunk.cast() >= end_of_address_space {
-            return None;
+            return Err(AllocError::OutOfSpace);
         }
 
         self.paranoid_check_chunk(old_next_chunk);
         let old_next_size = unsafe { old_next_chunk.get_unchecked(self.base_address).size };
         if old_next_size.is_allocated() {
-            return None;
+            return Err(AllocError::OutOfSpace);
         }
 
         let old_next_size = old_next_size.size();
         let available_space = current_size.unchecked_add(old_next_size);
         if available_space < new_size {
-            return None;
+            return Err(AllocError::OutOfSpace);
         }
 
         let remaining_free_space = available_space.unchecked_sub(new_size);
@@ -1052,7 +2282,7 @@ impl<E: Env> Allocator<E> {
 
         if self.allocated_space < end_offset {
             if !unsafe { self.env.expand_memory_until(self.base_address, end_offset) } {
-                return None;
+                return Err(AllocError::ExpansionRefused);
             }
 
             self.allocated_space = end_offset;
@@ -1062,9 +2292,18 @@ impl<E: Env> Allocator<E> {
             old_next_chunk.cast::<FreeChunkHeader>(),
             Self::size_to_bin_round_down(old_next_size),
         );
-        chunk.get_mut_unchecked(self.base_address).size = ChunkSize::new_allocated(new_size);
+        self.merge_into_allocation(chunk, available_space);
+        self.used_space = self.used_space.unchecked_add(new_size.unchecked_sub(current_size));
 
+        // The merged chunk is split again from its end, like in `alloc`.
         let chunk_size = self.register_free_space(new_next_chunk.cast::<FreeChunkHeader>(), new_size, remaining_free_space);
+        header_store_barrier();
+
+        let header = chunk.get_mut_unchecked(self.base_address);
+        header.size = ChunkSize::new_allocated(new_size);
+        header.unused_bytes = 0;
+        header_store_barrier();
+
         let final_chunk = new_next_chunk.unchecked_add(remaining_free_space);
         if final_chunk.cast() < end_of_address_space {
             self.paranoid_check_access(final_chunk);
@@ -1074,7 +2313,143 @@ impl<E: Env> Allocator<E> {
         self.paranoid_check_chunk(chunk);
         self.paranoid_check_chunk(new_next_chunk);
         self.paranoid_check_chunk(final_chunk);
-        Some(new_size.unchecked_sub(HEADER_SIZE))
+        Ok(new_size.unchecked_sub(HEADER_SIZE))
+    }
+
+    /// Tries to grow the memory allocation by merging it with the free chunk before it (and the one after it, if it's free),
+    /// moving the data down to the start of the merged chunk.
+    unsafe fn grow_backward_impl(&mut self, pointer: NonNull<u8>, align: Size, new_size: Size) -> Result<NonNull<u8>, AllocError> {
+        if align.0 == 0 || !align.0.is_power_of_two() {
+            return Err(AllocError::InvalidAlignment);
+        }
+
+        let Some(new_size_with_header) = new_size.checked_add(HEADER_SIZE) else {
+            return Err(AllocError::SizeOverflow);
+        };
+
+        let chunk = Pointer::from_pointer(pointer.as_ptr())
+            .unchecked_sub(HEADER_SIZE)
+            .cast::<ChunkHeader>();
+        self.paranoid_check_chunk(chunk);
+
+        let base_address = Pointer::from_pointer_mut(self.base_address);
+        let chunk_offset = Size::from_pointer_and_base_unchecked(chunk, base_address);
+        if chunk_offset.is_empty() {
+            return Err(AllocError::OutOfSpace);
+        }
+
+        let header = unsafe { chunk.get_unchecked(self.base_address) };
+        paranoid_assert!(header.size.is_allocated());
+
+        let current_size = header.size.size();
+        let prev_chunk = chunk.unchecked_sub(header.prev_chunk_size);
+        self.paranoid_check_chunk(prev_chunk);
+
+        let prev_header = unsafe { prev_chunk.get_unchecked(self.base_address) };
+        if prev_header.size.is_allocated() {
+            return Err(AllocError::OutOfSpace);
+        }
+
+        let prev_size = prev_header.size.size();
+        let prev_prev_chunk_size = prev_header.prev_chunk_size;
+
+        let end_of_address_space = Pointer::from_pointer(self.base_address).unchecked_add(self.env.total_space());
+        let old_next_chunk = chunk.unchecked_add(current_size);
+        let mut next_size = Size(0);
+        if old_next_chunk.cast() < end_of_address_space {
+            self.paranoid_check_chunk(old_next_chunk);
+            let size = unsafe { old_next_chunk.get_unchecked(self.base_address).size };
+            if !size.is_allocated() {
+                next_size = size.size();
+            }
+        }
+
+        let start_offset = chunk_offset.unchecked_sub(prev_size);
+        let data_offset = Size(align_offset(start_offset.0 + HEADER_SIZE.0, align.0, self.base_address.addr()));
+        let header_offset = data_offset.unchecked_sub(HEADER_SIZE);
+        if header_offset >= chunk_offset {
+            return Err(AllocError::OutOfSpace);
+        }
+
+        let available_space = chunk_offset
+            .unchecked_add(current_size)
+            .unchecked_add(next_size)
+            .unchecked_sub(header_offset);
+        if available_space < new_size_with_header {
+            return Err(AllocError::OutOfSpace);
+        }
+
+        if !self.is_within_budget(new_size_with_header.unchecked_sub(current_size), Size(0)) {
+            return Err(AllocError::BudgetExceeded);
+        }
+
+        let free_space_lhs = header_offset.unchecked_sub(start_offset);
+        let free_space_rhs = available_space.unchecked_sub(new_size_with_header);
+
+        let mut end_offset = data_offset.unchecked_add(new_size);
+        if !free_space_rhs.is_empty() {
+            end_offset = end_offset.unchecked_add(FREE_CHUNK_HEADER_SIZE);
+        }
+
+        if self.allocated_space < end_offset {
+            if !unsafe { self.env.expand_memory_until(self.base_address, end_offset) } {
+                return Err(AllocError::ExpansionRefused);
+            }
+
+            self.allocated_space = end_offset;
+        }
+
+        self.unregister_free_space(prev_chunk.cast::<FreeChunkHeader>(), Self::size_to_bin_round_down(prev_size));
+        if !next_size.is_empty() {
+            self.unregister_free_space(old_next_chunk.cast::<FreeChunkHeader>(), Self::size_to_bin_round_down(next_size));
+        }
+
+        // Everything is merged into a single allocation first, so that the old header can be overwritten by the data.
+        let total_size = available_space.unchecked_add(free_space_lhs);
+        self.merge_into_allocation(prev_chunk, total_size);
+
+        // The old and the new data can overlap, so this must be done before any of the new headers are written.
+        let start_chunk = prev_chunk.cast::<FreeChunkHeader>();
+        let allocation_chunk = base_address.unchecked_add(header_offset).cast::<ChunkHeader>();
+        let new_pointer = allocation_chunk
+            .unchecked_add(HEADER_SIZE)
+            .cast::<u8>()
+            .raw_pointer_mut(self.base_address);
+        core::ptr::copy(
+            pointer.as_ptr(),
+            new_pointer,
+            current_size.unchecked_sub(HEADER_SIZE).bytes() as usize,
+        );
+
+        // The merged chunk is split again from its end, like in `alloc`.
+        let new_next_chunk = allocation_chunk.unchecked_add(new_size_with_header).cast::<FreeChunkHeader>();
+        let chunk_size = self.register_free_space(new_next_chunk, new_size_with_header, free_space_rhs);
+        header_store_barrier();
+
+        let lhs_chunk_size = if free_space_lhs.is_empty() {
+            prev_prev_chunk_size
+        } else {
+            free_space_lhs
+        };
+        self.register_allocation(allocation_chunk, lhs_chunk_size, new_size_with_header, 0);
+        header_store_barrier();
+
+        self.register_free_space(start_chunk, prev_prev_chunk_size, free_space_lhs);
+        header_store_barrier();
+        self.used_space = self.used_space.unchecked_add(new_size_with_header.unchecked_sub(current_size));
+
+        let final_chunk = new_next_chunk.unchecked_add(free_space_rhs);
+        if final_chunk.cast() < end_of_address_space {
+            self.paranoid_check_access(final_chunk);
+            final_chunk.get_mut_unchecked(self.base_address).prev_chunk_size = chunk_size;
+        }
+
+        self.paranoid_check_chunk(start_chunk.cast());
+        self.paranoid_check_chunk(allocation_chunk);
+        self.paranoid_check_chunk(final_chunk.cast());
+        paranoid_assert_eq!(Pointer::from_pointer(new_pointer).address() % align.bytes() as Address, 0);
+
+        Ok(unsafe { NonNull::new_unchecked(new_pointer) })
     }
 
     /// Reallocates the memory pointed by `pointer`.
@@ -1083,32 +2458,96 @@ impl<E: Env> Allocator<E> {
     ///
     /// The `pointer` must have come from [`Allocator::alloc`](Allocator::alloc), and must not have been passed to [`Allocator::free`](Allocator::free) beforehand.
     pub unsafe fn realloc(&mut self, pointer: NonNull<u8>, align: Size, new_size: Size) -> Option<NonNull<u8>> {
+        #[cfg(feature = "trace")]
+        let handle = self.trace_handle(pointer);
+
+        let new_pointer = if new_size.is_empty() && !Self::usable_size_impl(pointer).is_empty() {
+            self.free_impl(pointer);
+            None
+        } else {
+            self.realloc_impl(pointer, align, new_size).ok()
+        };
+
+        #[cfg(feature = "trace")]
+        self.trace_realloc(handle, align, new_size, new_pointer);
+
+        new_pointer
+    }
+
+    /// Reallocates the memory pointed by `pointer`, returning it together with its whole usable size.
+    ///
+    /// This behaves exactly like [`Allocator::realloc`](Allocator::realloc); the usable size can be bigger than `new_size`.
+    ///
+    /// # Safety
+    ///
+    /// The `pointer` must have come from [`Allocator::alloc`](Allocator::alloc), and must not have been passed to [`Allocator::free`](Allocator::free) beforehand.
+    #[inline]
+    pub unsafe fn realloc_sized(&mut self, pointer: NonNull<u8>, align: Size, new_size: Size) -> Option<NonNull<[u8]>> {
+        let pointer = self.realloc(pointer, align, new_size)?;
+        Some(Self::sized(pointer))
+    }
+
+    /// Reallocates the memory pointed by `pointer`, returning the reason of the failure if it fails.
+    ///
+    /// Unlike [`Allocator::realloc`](Allocator::realloc) this never frees the memory when `new_size` is zero;
+    /// the allocation is shrunk instead. On failure the original allocation is left untouched.
+    ///
+    /// # Safety
+    ///
+    /// The `pointer` must have come from [`Allocator::alloc`](Allocator::alloc), and must not have been passed to [`Allocator::free`](Allocator::free) beforehand.
+    pub unsafe fn try_realloc(&mut self, pointer: NonNull<u8>, align: Size, new_size: Size) -> Result<NonNull<u8>, AllocError> {
+        #[cfg(feature = "trace")]
+        let handle = self.trace_handle(pointer);
+
+        let result = self.realloc_impl(pointer, align, new_size);
+
+        #[cfg(feature = "trace")]
+        self.trace_realloc(handle, align, new_size, result.ok());
+
+        result
+    }
+
+    #[cfg(feature = "trace")]
+    #[inline(never)]
+    fn trace_realloc(&mut self, handle: u32, align: Size, size: Size, pointer: Option<NonNull<u8>>) {
+        let result = pointer.map(|pointer| self.trace_handle(pointer));
+        self.env.trace(crate::TraceEvent::Realloc {
+            handle,
+            align,
+            size,
+            result,
+        });
+    }
+
+    unsafe fn realloc_impl(&mut self, pointer: NonNull<u8>, align: Size, new_size: Size) -> Result<NonNull<u8>, AllocError> {
         let current_size = Self::usable_size_impl(pointer);
         if new_size == current_size {
-            return Some(pointer);
-        }
-
-        if new_size.is_empty() {
-            self.free(pointer);
-            return None;
+            Self::set_requested_bytes(pointer, new_size.bytes() as usize);
+            return Ok(pointer);
         }
 
         if cfg!(feature = "realloc_inplace") {
             if new_size < current_size {
-                self.shrink_inplace(pointer, new_size);
-                return Some(pointer);
+                self.shrink_chunk(pointer, new_size);
+                return Ok(pointer);
+            }
+
+            if self.grow_inplace_impl(pointer, new_size).is_ok() {
+                return Ok(pointer);
             }
 
-            if self.grow_inplace(pointer, new_size).is_some() {
-                return Some(pointer);
+            if let Ok(new_pointer) = self.grow_backward_impl(pointer, align, new_size) {
+                return Ok(new_pointer);
             }
         }
 
-        let new_pointer = self.alloc(align, new_size)?;
-        core::ptr::copy_nonoverlapping(pointer.as_ptr(), new_pointer.as_ptr(), current_size.bytes() as usize);
-        self.free(pointer);
+        // The old allocation is freed right afterwards, so it doesn't count against the budget of the new one.
+        let new_pointer = self.alloc_with_retry(align, new_size, false, current_size.unchecked_add(HEADER_SIZE))?;
+        let copy_size = core::cmp::min(current_size, new_size);
+        core::ptr::copy_nonoverlapping(pointer.as_ptr(), new_pointer.as_ptr(), copy_size.bytes() as usize);
+        self.free_impl(pointer);
 
-        Some(new_pointer)
+        Ok(new_pointer)
     }
 
     /// Frees the memory pointed by `pointer`.
@@ -1117,6 +2556,88 @@ impl<E: Env> Allocator<E> {
     ///
     /// The `pointer` must have come from [`Allocator::alloc`](Allocator::alloc), and must not have been passed to [`Allocator::free`](Allocator::free) beforehand.
     pub unsafe fn free(&mut self, pointer: NonNull<u8>) {
+        #[cfg(feature = "trace")]
+        let handle = self.trace_handle(pointer);
+
+        self.free_impl(pointer);
+
+        #[cfg(feature = "trace")]
+        self.env.trace(crate::TraceEvent::Free { handle });
+    }
+
+    /// Frees all of the given allocations.
+    ///
+    /// # Safety
+    ///
+    /// Every pointer must have come from [`Allocator::alloc`](Allocator::alloc), must not have been passed to [`Allocator::free`](Allocator::free) beforehand,
+    /// and must appear only once.
+    pub unsafe fn free_batch(&mut self, pointers: &[NonNull<u8>]) {
+        for &pointer in pointers {
+            self.free(pointer);
+        }
+    }
+
+    /// Frees all of the allocations at once.
+    ///
+    /// This only takes time proportional to the number of non-empty bins, no matter how many allocations there are.
+    ///
+    /// # Safety
+    ///
+    /// None of the pointers returned by this allocator can be used afterwards.
+    pub unsafe fn reset(&mut self) {
+        self.reset_impl(None);
+    }
+
+    /// Frees all of the allocations at once, like [`Allocator::reset`](Allocator::reset), and asks the environment
+    /// to release the memory past the first `watermark` bytes of the heap through [`Env::decommit_memory_after`](Env::decommit_memory_after).
+    ///
+    /// # Safety
+    ///
+    /// None of the pointers returned by this allocator can be used afterwards.
+    pub unsafe fn reset_and_decommit(&mut self, watermark: Size) {
+        self.reset_impl(Some(watermark));
+    }
+
+    unsafe fn reset_impl(&mut self, watermark: Option<Size>) {
+        if self.base_address.is_null() {
+            return;
+        }
+
+        // Only the non-empty bins need to be cleared.
+        let mut next_bin = self.free_lists_with_unallocated_memory.find_first(BitMask::index(0));
+        while let Some(bin) = next_bin {
+            *get_mut_unchecked(&mut self.first_in_free_list, bin.index()) = Link::NULL;
+            next_bin = if bin.index + 1 < BIN_CONFIG.bin_count {
+                self.free_lists_with_unallocated_memory.find_first(BitMask::index(bin.index + 1))
+            } else {
+                None
+            };
+        }
+
+        self.free_lists_with_unallocated_memory = BitMask::new();
+        self.used_space = Size(0);
+        self.allocation_count = 0;
+
+        if let Some(watermark) = watermark {
+            // The header of the free chunk must stay accessible.
+            let watermark = core::cmp::max(watermark, FREE_CHUNK_HEADER_SIZE);
+            if watermark < self.allocated_space {
+                if let Some(size) = self.env.decommit_memory_after(self.base_address, watermark) {
+                    paranoid_assert!(size >= watermark);
+                    self.allocated_space = core::cmp::min(size, self.allocated_space);
+                }
+            }
+        }
+
+        let total_space = self.env.total_space();
+        self.register_free_space(Pointer::from_pointer_mut(self.base_address).cast(), Size(0), total_space);
+        self.paranoid_check_chunk(Pointer::from_pointer_mut(self.base_address).cast());
+
+        #[cfg(feature = "trace")]
+        self.env.trace(crate::TraceEvent::Reset);
+    }
+
+    unsafe fn free_impl(&mut self, pointer: NonNull<u8>) {
         let pointer = pointer.as_ptr();
 
         paranoid_assert!(!self.base_address.is_null());
@@ -1129,6 +2650,8 @@ impl<E: Env> Allocator<E> {
 
         paranoid_assert!(size.is_allocated());
         let mut size = size.size();
+        self.used_space = self.used_space.unchecked_sub(size.unchecked_sub(HEADER_SIZE));
+        self.allocation_count -= 1;
 
         // Try to merge with the previous free chunk.
         if !Size::from_pointer_and_base_unchecked(chunk, Pointer::from_pointer_mut(self.base_address)).is_empty() {
@@ -1163,9 +2686,8 @@ impl<E: Env> Allocator<E> {
             }
         }
 
+        // The merged chunk only becomes visible at the very end, after the chunk following it already points to its start.
         let chunk = chunk.cast::<FreeChunkHeader>();
-        self.register_free_space(chunk, prev_chunk_size, size);
-
         let next_chunk = chunk.unchecked_add(size);
         if next_chunk.cast() < end_of_address_space {
             self.paranoid_check_access(next_chunk);
@@ -1174,11 +2696,15 @@ impl<E: Env> Allocator<E> {
             };
         }
 
+        header_store_barrier();
+        self.register_free_space(chunk, prev_chunk_size, size);
         self.paranoid_check_chunk(chunk.cast());
     }
 
     /// Returns the amount of usable space in the memory pointed by `pointer`.
     ///
+    /// This doesn't check the pointer in any way; see [`Allocator::allocation_info`](Allocator::allocation_info) for a checked alternative.
+    ///
     /// # Safety
     ///
     /// The `pointer` must have come from [`Allocator::alloc`](Allocator::alloc), and must not have been passed to [`Allocator::free`](Allocator::free) beforehand.
@@ -1196,43 +2722,56 @@ impl<E: Env> Allocator<E> {
     unsafe fn header_for_pointer<'a>(pointer: *mut u8) -> &'a ChunkHeader {
         unsafe { &*pointer.byte_sub(HEADER_SIZE.bytes() as usize).cast::<ChunkHeader>() }
     }
+
+    /// Records that only the first `bytes` of the allocation were requested, for [`Fragmentation::rounding_waste`].
+    #[inline]
+    pub(crate) unsafe fn set_requested_bytes(pointer: NonNull<u8>, bytes: usize) {
+        let header = unsafe { &mut *pointer.as_ptr().byte_sub(HEADER_SIZE.bytes() as usize).cast::<ChunkHeader>() };
+        let usable_bytes = header.size.size().unchecked_sub(HEADER_SIZE).bytes();
+        header.unused_bytes = usable_bytes - core::cmp::min(bytes, usable_bytes as usize) as u32;
+    }
 }
 
-#[cfg(target_has_atomic = "8")]
-unsafe impl<E: crate::Env> core::alloc::GlobalAlloc for crate::Mutex<Allocator<E>> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let Some(align) = Size::from_bytes_usize(layout.align()) else {
-            return core::ptr::null_mut();
-        };
+/// Converts a `Layout` into an alignment and a size.
+#[cfg(any(target_has_atomic = "8", feature = "lock_api"))]
+#[inline]
+fn layout_to_size(layout: core::alloc::Layout) -> Option<(Size, Size)> {
+    Some((Size::from_bytes_usize(layout.align())?, Size::from_bytes_usize(layout.size())?))
+}
 
-        let Some(size) = Size::from_bytes_usize(layout.size()) else {
+// These are shared by all of the `GlobalAlloc` implementations.
+#[cfg(any(target_has_atomic = "8", feature = "lock_api"))]
+impl<E: crate::Env> Allocator<E> {
+    #[inline]
+    fn global_alloc(&mut self, layout: core::alloc::Layout) -> *mut u8 {
+        let Some((align, size)) = layout_to_size(layout) else {
             return core::ptr::null_mut();
         };
 
-        if let Some(pointer) = self.lock().alloc(align, size) {
+        if let Some(pointer) = self.alloc(align, size) {
+            unsafe { Self::set_requested_bytes(pointer, layout.size()) };
             pointer.as_ptr()
         } else {
             core::ptr::null_mut()
         }
     }
 
-    unsafe fn alloc_zeroed(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let Some(align) = Size::from_bytes_usize(layout.align()) else {
-            return core::ptr::null_mut();
-        };
-
-        let Some(size) = Size::from_bytes_usize(layout.size()) else {
+    #[inline]
+    fn global_alloc_zeroed(&mut self, layout: core::alloc::Layout) -> *mut u8 {
+        let Some((align, size)) = layout_to_size(layout) else {
             return core::ptr::null_mut();
         };
 
-        if let Some(pointer) = self.lock().alloc_zeroed(align, size) {
+        if let Some(pointer) = self.alloc_zeroed(align, size) {
+            unsafe { Self::set_requested_bytes(pointer, layout.size()) };
             pointer.as_ptr()
         } else {
             core::ptr::null_mut()
         }
     }
 
-    unsafe fn realloc(&self, pointer: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
+    #[inline]
+    unsafe fn global_realloc(&mut self, pointer: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
         let Some(pointer) = NonNull::new(pointer) else {
             return core::ptr::null_mut();
         };
@@ -1241,20 +2780,125 @@ unsafe impl<E: crate::Env> core::alloc::GlobalAlloc for crate::Mutex<Allocator<E
             return core::ptr::null_mut();
         };
 
-        let Some(new_size) = Size::from_bytes_usize(new_size) else {
+        let Some(new_size_in_granules) = Size::from_bytes_usize(new_size) else {
             return core::ptr::null_mut();
         };
 
-        if let Some(pointer) = self.lock().realloc(pointer, align, new_size) {
+        if let Some(pointer) = self.realloc(pointer, align, new_size_in_granules) {
+            Self::set_requested_bytes(pointer, new_size);
             pointer.as_ptr()
         } else {
             core::ptr::null_mut()
         }
     }
 
-    unsafe fn dealloc(&self, pointer: *mut u8, _layout: core::alloc::Layout) {
+    #[inline]
+    unsafe fn global_dealloc(&mut self, pointer: *mut u8) {
         if let Some(pointer) = NonNull::new(pointer) {
-            self.lock().free(pointer);
+            self.free(pointer);
+        }
+    }
+}
+
+#[cfg(target_has_atomic = "8")]
+unsafe impl<E: crate::Env> core::alloc::GlobalAlloc for crate::Mutex<Allocator<E>> {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.lock().global_alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.lock().global_alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
+        self.lock().global_realloc(pointer, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, _layout: core::alloc::Layout) {
+        self.lock().global_dealloc(pointer);
+    }
+}
+
+#[cfg(target_has_atomic = "8")]
+impl<E: crate::Env> crate::Mutex<Allocator<E>> {
+    /// Allocates up to `out.len()` objects with the given `layout` while taking the lock only once.
+    ///
+    /// See [`Allocator::alloc_batch`](Allocator::alloc_batch) for details.
+    pub fn alloc_batch(&self, layout: core::alloc::Layout, out: &mut [MaybeUninit<NonNull<u8>>]) -> usize {
+        let Some((align, size)) = layout_to_size(layout) else {
+            return 0;
+        };
+
+        let mut allocator = self.lock();
+        let count = allocator.alloc_batch(align, size, out);
+        for pointer in &out[..count] {
+            unsafe { Allocator::<E>::set_requested_bytes(pointer.assume_init(), layout.size()) };
         }
+
+        count
+    }
+
+    /// Frees all of the given allocations while taking the lock only once.
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`Allocator::free_batch`](Allocator::free_batch).
+    pub unsafe fn free_batch(&self, pointers: &[NonNull<u8>]) {
+        self.lock().free_batch(pointers);
+    }
+}
+
+/// An [`Allocator`] protected by any [`lock_api::RawMutex`], which can be used as a global allocator.
+///
+/// `GlobalAlloc` can't be implemented for [`lock_api::Mutex`] directly since neither of them is defined in this crate, so it's wrapped instead.
+#[cfg(feature = "lock_api")]
+pub struct LockApiAllocator<R, E: crate::Env>(lock_api::Mutex<R, Allocator<E>>);
+
+#[cfg(feature = "lock_api")]
+impl<R: lock_api::RawMutex, E: crate::Env> LockApiAllocator<R, E> {
+    /// Wraps the `allocator` in a new mutex.
+    pub const fn new(allocator: Allocator<E>) -> Self {
+        LockApiAllocator(lock_api::Mutex::new(allocator))
+    }
+
+    /// Returns the underlying mutex.
+    pub fn into_inner(self) -> lock_api::Mutex<R, Allocator<E>> {
+        self.0
+    }
+}
+
+#[cfg(feature = "lock_api")]
+impl<R, E: crate::Env> From<lock_api::Mutex<R, Allocator<E>>> for LockApiAllocator<R, E> {
+    fn from(mutex: lock_api::Mutex<R, Allocator<E>>) -> Self {
+        LockApiAllocator(mutex)
+    }
+}
+
+#[cfg(feature = "lock_api")]
+impl<R, E: crate::Env> core::ops::Deref for LockApiAllocator<R, E> {
+    type Target = lock_api::Mutex<R, Allocator<E>>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(feature = "lock_api")]
+unsafe impl<R: lock_api::RawMutex, E: crate::Env> core::alloc::GlobalAlloc for LockApiAllocator<R, E> {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.0.lock().global_alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.0.lock().global_alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
+        self.0.lock().global_realloc(pointer, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, _layout: core::alloc::Layout) {
+        self.0.lock().global_dealloc(pointer);
     }
 }
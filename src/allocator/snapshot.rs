@@ -0,0 +1,435 @@
+use super::{
+    Address, Allocator, BitMask, ChunkHeader, Link, Pointer, Size, SizeT, ALLOCATION_GRANULARITY, BIN_CONFIG, FREE_CHUNK_HEADER_SIZE,
+    HEADER_SIZE,
+};
+use crate::Env;
+
+/// The magic bytes which every heap snapshot starts with.
+const SNAPSHOT_MAGIC: [u8; 8] = *b"picosnap";
+
+/// The version of the snapshot format; bumped on every incompatible change.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Set when the links in the free chunks are relative to the start of the heap.
+const FLAG_RELOCATABLE: u32 = 1 << 0;
+
+const FLAGS: u32 = if cfg!(feature = "relocatable") { FLAG_RELOCATABLE } else { 0 };
+
+const BIN_COUNT: usize = BIN_CONFIG.bin_count as usize;
+const HEADER_LENGTH: usize = 44;
+const BITMAP_LENGTH: usize = BIN_COUNT.div_ceil(8);
+const FREE_LISTS_LENGTH: usize = BIN_COUNT * 4;
+
+/// Marks an empty free list.
+const NULL_OFFSET: u32 = u32::MAX;
+
+/// The reason why a snapshot couldn't be written or restored.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SnapshotError {
+    /// The buffer is smaller than [`Allocator::snapshot_size`](Allocator::snapshot_size).
+    BufferTooSmall,
+    /// The input doesn't start with the snapshot magic bytes.
+    InvalidMagic,
+    /// The snapshot was made with an unsupported version of the format.
+    UnsupportedVersion,
+    /// The snapshot was made by a build with a different heap layout, e.g. with a different pointer width
+    /// or with the `relocatable` feature toggled.
+    IncompatibleLayout,
+    /// The total space of the environment is different than the one the snapshot was made with.
+    TotalSpaceMismatch,
+    /// The input ends before the end of the snapshot.
+    Truncated,
+    /// The allocator state in the snapshot is inconsistent.
+    Corrupted,
+    /// The environment failed to allocate the address space for the heap, or to make it accessible.
+    InitializationFailed,
+}
+
+impl core::fmt::Display for SnapshotError {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let message = match self {
+            SnapshotError::BufferTooSmall => "buffer is too small",
+            SnapshotError::InvalidMagic => "not a heap snapshot",
+            SnapshotError::UnsupportedVersion => "unsupported snapshot version",
+            SnapshotError::IncompatibleLayout => "snapshot has an incompatible heap layout",
+            SnapshotError::TotalSpaceMismatch => "snapshot has a different total space",
+            SnapshotError::Truncated => "snapshot is truncated",
+            SnapshotError::Corrupted => "snapshot is corrupted",
+            SnapshotError::InitializationFailed => "failed to allocate the address space",
+        };
+
+        fmt.write_str(message)
+    }
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    position: usize,
+}
+
+impl Writer<'_> {
+    #[inline]
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    #[inline]
+    fn push_u32(&mut self, value: u32) {
+        self.push(&value.to_le_bytes());
+    }
+
+    #[inline]
+    fn push_u64(&mut self, value: u64) {
+        self.push(&value.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    #[inline]
+    fn pop(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+        let bytes = self
+            .bytes
+            .get(self.position..)
+            .and_then(|bytes| bytes.get(..length))
+            .ok_or(SnapshotError::Truncated)?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    #[inline]
+    fn pop_array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        self.pop(N)?.first_chunk::<N>().copied().ok_or(SnapshotError::Truncated)
+    }
+
+    #[inline]
+    fn pop_u32(&mut self) -> Result<u32, SnapshotError> {
+        self.pop_array().map(u32::from_le_bytes)
+    }
+
+    #[inline]
+    fn pop_u64(&mut self) -> Result<u64, SnapshotError> {
+        self.pop_array().map(u64::from_le_bytes)
+    }
+
+    #[inline]
+    fn pop_size(&mut self) -> Result<Size, SnapshotError> {
+        let bytes = self.pop_u32()?;
+        if bytes % ALLOCATION_GRANULARITY != 0 {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        Ok(Size::from_bytes(bytes))
+    }
+}
+
+impl Size {
+    #[inline]
+    const fn from_bytes(bytes: SizeT) -> Self {
+        Size(bytes / ALLOCATION_GRANULARITY)
+    }
+}
+
+impl<E: Env> Allocator<E> {
+    /// Returns the number of bytes needed to hold a snapshot of the allocator.
+    pub fn snapshot_size(&self) -> usize {
+        HEADER_LENGTH + BITMAP_LENGTH + FREE_LISTS_LENGTH + self.allocated_space.bytes() as usize
+    }
+
+    /// Writes the state of the allocator, along with the used part of the heap, into `buffer`.
+    ///
+    /// Returns the number of bytes written. The budget, the fit strategy and the out-of-memory handler are not part of the snapshot.
+    pub fn snapshot(&self, buffer: &mut [u8]) -> Result<usize, SnapshotError> {
+        let length = self.snapshot_size();
+        let Some(buffer) = buffer.get_mut(..length) else {
+            return Err(SnapshotError::BufferTooSmall);
+        };
+
+        let mut writer = Writer { buffer, position: 0 };
+        writer.push(&SNAPSHOT_MAGIC);
+        writer.push_u32(SNAPSHOT_VERSION);
+        writer.push_u32(FLAGS);
+        writer.push_u64(self.base_address.addr() as u64);
+        writer.push_u32(core::mem::size_of::<Address>() as u32);
+        writer.push_u32(BIN_CONFIG.bin_count);
+        writer.push_u32(self.env.total_space().bytes());
+        writer.push_u32(self.allocated_space.bytes());
+        writer.push_u32(self.used_space.bytes());
+        paranoid_assert_eq!(writer.position, HEADER_LENGTH);
+
+        let mut bitmap = [0; BITMAP_LENGTH];
+        for bin in 0..BIN_CONFIG.bin_count {
+            if self.free_lists_with_unallocated_memory.is_set(BitMask::index(bin)) {
+                bitmap[bin as usize / 8] |= 1 << (bin % 8);
+            }
+        }
+        writer.push(&bitmap);
+
+        for link in self.first_in_free_list {
+            writer.push_u32(if link.is_null() {
+                NULL_OFFSET
+            } else {
+                link.offset(self.base_address) as u32
+            });
+        }
+
+        if !self.base_address.is_null() {
+            writer.push(unsafe { core::slice::from_raw_parts(self.base_address, self.allocated_space.bytes() as usize) });
+        }
+
+        paranoid_assert_eq!(writer.position, length);
+        Ok(length)
+    }
+
+    /// Creates an allocator from a snapshot made with [`Allocator::snapshot`](Allocator::snapshot).
+    ///
+    /// The environment must have the same total space as the one the snapshot was made with, but the heap
+    /// doesn't have to be at the same address. Any bytes after the end of the snapshot are ignored.
+    ///
+    /// # Safety
+    ///
+    /// The snapshot is validated, but only enough to catch accidental corruption; a maliciously
+    /// crafted snapshot can still result in a corrupted heap.
+    pub unsafe fn restore(env: E, snapshot: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader {
+            bytes: snapshot,
+            position: 0,
+        };
+        if reader.pop_array::<8>().ok() != Some(SNAPSHOT_MAGIC) {
+            return Err(SnapshotError::InvalidMagic);
+        }
+
+        if reader.pop_u32()? != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion);
+        }
+
+        let flags = reader.pop_u32()?;
+        let old_base_address = reader.pop_u64()?;
+        let address_size = reader.pop_u32()?;
+        let bin_count = reader.pop_u32()?;
+        if flags != FLAGS || address_size != core::mem::size_of::<Address>() as u32 || bin_count != BIN_CONFIG.bin_count {
+            return Err(SnapshotError::IncompatibleLayout);
+        }
+
+        let total_space = reader.pop_size()?;
+        let allocated_space = reader.pop_size()?;
+        let used_space = reader.pop_size()?;
+        if total_space != env.total_space() {
+            return Err(SnapshotError::TotalSpaceMismatch);
+        }
+
+        if allocated_space > total_space || used_space > allocated_space {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        let bitmap = reader.pop(BITMAP_LENGTH)?;
+        let mut heads = [NULL_OFFSET; BIN_COUNT];
+        for (bin, head) in heads.iter_mut().enumerate() {
+            *head = reader.pop_u32()?;
+            if (*head != NULL_OFFSET) != ((bitmap[bin / 8] & (1 << (bin % 8))) != 0) {
+                return Err(SnapshotError::Corrupted);
+            }
+        }
+
+        let heap = reader.pop(allocated_space.bytes() as usize)?;
+        let mut allocator = Allocator::new(env);
+        if allocated_space.is_empty() {
+            // The heap was never initialized.
+            if !used_space.is_empty() || heads.iter().any(|&head| head != NULL_OFFSET) {
+                return Err(SnapshotError::Corrupted);
+            }
+
+            return Ok(allocator);
+        }
+
+        let base_address = allocator.env.allocate_address_space();
+        if base_address.is_null() {
+            return Err(SnapshotError::InitializationFailed);
+        }
+
+        // From now on the address space will be released when the allocator is dropped.
+        allocator.base_address = base_address;
+        if base_address.addr() % ALLOCATION_GRANULARITY as usize != 0 || !allocator.env.expand_memory_until(base_address, allocated_space) {
+            return Err(SnapshotError::InitializationFailed);
+        }
+
+        core::ptr::copy_nonoverlapping(heap.as_ptr(), base_address, heap.len());
+        allocator.allocated_space = allocated_space;
+        allocator.used_space = used_space;
+
+        let (free_chunks, actual_used_space, allocation_count) = allocator.validate_chunks()?;
+        if actual_used_space != used_space {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        allocator.allocation_count = allocation_count;
+
+        let old_base_address = core::ptr::without_provenance_mut(old_base_address as usize);
+        if allocator.restore_free_lists(&heads, old_base_address)? != free_chunks {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        Ok(allocator)
+    }
+
+    #[inline]
+    fn chunk_at(&self, offset: Size) -> Pointer<ChunkHeader> {
+        Pointer::from_pointer_mut(self.base_address).unchecked_add(offset).cast()
+    }
+
+    /// Walks over every chunk in the heap and checks that they're consistent.
+    ///
+    /// Returns the number of free chunks, the total usable space of the allocated chunks and their number.
+    fn validate_chunks(&self) -> Result<(usize, Size, SizeT), SnapshotError> {
+        let total_space = self.env.total_space();
+        let mut offset = Size(0);
+        let mut prev_chunk_size = Size(0);
+        let mut is_prev_chunk_free = false;
+        let mut free_chunks = 0;
+        let mut used_space = Size(0);
+        let mut allocation_count = 0;
+        while offset < total_space {
+            if offset.unchecked_add(HEADER_SIZE) > self.allocated_space {
+                return Err(SnapshotError::Corrupted);
+            }
+
+            let header = unsafe { *self.chunk_at(offset).get_unchecked(self.base_address) };
+            let size = header.size.size();
+            if size.is_empty() || size > total_space.unchecked_sub(offset) || header.prev_chunk_size != prev_chunk_size {
+                return Err(SnapshotError::Corrupted);
+            }
+
+            if header.size.is_allocated() {
+                if offset.unchecked_add(size) > self.allocated_space {
+                    return Err(SnapshotError::Corrupted);
+                }
+
+                used_space = used_space.unchecked_add(size.unchecked_sub(HEADER_SIZE));
+                allocation_count += 1;
+                is_prev_chunk_free = false;
+            } else {
+                // Adjacent free chunks are always merged.
+                if is_prev_chunk_free {
+                    return Err(SnapshotError::Corrupted);
+                }
+
+                free_chunks += 1;
+                is_prev_chunk_free = true;
+            }
+
+            prev_chunk_size = size;
+            offset = offset.unchecked_add(size);
+        }
+
+        Ok((free_chunks, used_space, allocation_count))
+    }
+
+    /// Returns a link to the free chunk at `offset` bytes from the start of the heap, if it's in bounds.
+    #[inline]
+    fn checked_link(&self, offset: Address) -> Result<Link, SnapshotError> {
+        let is_valid = offset % Address::from(ALLOCATION_GRANULARITY) == 0
+            && offset
+                .checked_add(Address::from(FREE_CHUNK_HEADER_SIZE.bytes()))
+                .is_some_and(|end| end <= Address::from(self.allocated_space.bytes()));
+        if !is_valid {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        Ok(Link::from_offset(Size::from_bytes(offset as SizeT), self.base_address))
+    }
+
+    /// Rebuilds the free lists from the snapshot, converting the links stored in the free chunks
+    /// from `old_base_address` to the current base address, and checks that they're consistent.
+    ///
+    /// Must be called after [`Allocator::validate_chunks`]. Returns the number of free chunks in the lists.
+    fn restore_free_lists(&mut self, heads: &[u32; BIN_COUNT], old_base_address: *mut u8) -> Result<usize, SnapshotError> {
+        let total_space = self.env.total_space();
+        let max_free_chunks = self.allocated_space.0 as usize;
+        let mut free_chunks = 0;
+        for (bin, &head) in heads.iter().enumerate() {
+            if head == NULL_OFFSET {
+                continue;
+            }
+
+            let mut link = self.checked_link(Address::from(head))?;
+            self.first_in_free_list[bin] = link;
+            self.free_lists_with_unallocated_memory.set(BitMask::index(bin as u32));
+
+            let mut prev_in_list = Link::NULL;
+            loop {
+                free_chunks += 1;
+                if free_chunks > max_free_chunks {
+                    return Err(SnapshotError::Corrupted);
+                }
+
+                let chunk = link.get(self.base_address);
+                let offset = Size::from_pointer_and_base_unchecked(chunk, Pointer::from_pointer_mut(self.base_address));
+                let chunk_ref = unsafe { chunk.get_mut_unchecked(self.base_address) };
+                let size = chunk_ref.size.size();
+                if chunk_ref.size.is_allocated()
+                    || size.is_empty()
+                    || size > total_space.unchecked_sub(offset)
+                    || Self::size_to_bin_round_down(size).index() != bin
+                {
+                    return Err(SnapshotError::Corrupted);
+                }
+
+                // Make sure that this is an actual chunk and not something in the middle of one.
+                let is_prev_chunk_valid = if chunk_ref.prev_chunk_size.is_empty() {
+                    offset.is_empty()
+                } else {
+                    chunk_ref.prev_chunk_size <= offset
+                        && unsafe {
+                            self.chunk_at(offset.unchecked_sub(chunk_ref.prev_chunk_size))
+                                .get_unchecked(self.base_address)
+                        }
+                        .size
+                        .size()
+                            == chunk_ref.prev_chunk_size
+                };
+
+                let next_offset = offset.unchecked_add(size);
+                let is_next_chunk_valid = next_offset == total_space
+                    || next_offset.unchecked_add(HEADER_SIZE) <= self.allocated_space
+                        && unsafe { self.chunk_at(next_offset).get_unchecked(self.base_address) }.prev_chunk_size == size;
+                if !is_prev_chunk_valid || !is_next_chunk_valid {
+                    return Err(SnapshotError::Corrupted);
+                }
+
+                let old_prev_in_list = chunk_ref.prev_in_list;
+                let is_prev_in_list_valid = if prev_in_list.is_null() {
+                    old_prev_in_list.is_null()
+                } else {
+                    !old_prev_in_list.is_null() && old_prev_in_list.offset(old_base_address) == prev_in_list.offset(self.base_address)
+                };
+
+                if !is_prev_in_list_valid {
+                    return Err(SnapshotError::Corrupted);
+                }
+
+                let old_next_in_list = chunk_ref.next_in_list;
+                let next_in_list = if old_next_in_list.is_null() {
+                    Link::NULL
+                } else {
+                    self.checked_link(old_next_in_list.offset(old_base_address))?
+                };
+
+                chunk_ref.prev_in_list = prev_in_list;
+                chunk_ref.next_in_list = next_in_list;
+                if next_in_list.is_null() {
+                    break;
+                }
+
+                prev_in_list = link;
+                link = next_in_list;
+            }
+        }
+
+        Ok(free_chunks)
+    }
+}
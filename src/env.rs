@@ -2,11 +2,16 @@ use crate::Size;
 
 #[cold]
 pub fn abort() -> ! {
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
     unsafe {
         core::arch::asm!("ud2", options(noreturn, nostack));
     }
 
+    #[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+    unsafe {
+        core::arch::asm!("udf #0", options(noreturn, nostack));
+    }
+
     #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
     unsafe {
         core::arch::asm!("unimp", options(noreturn, nostack));
@@ -17,15 +22,62 @@ pub fn abort() -> ! {
         core::arch::wasm32::unreachable();
     }
 
-    #[cfg(not(any(target_arch = "x86_64", target_arch = "riscv32", target_arch = "riscv64", target_family = "wasm")))]
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "aarch64",
+        target_arch = "arm",
+        target_arch = "riscv32",
+        target_arch = "riscv64",
+        target_family = "wasm"
+    )))]
     unreachable!();
 }
 
 pub trait Env {
+    /// Returns the total size of the address space managed by the allocator.
     fn total_space(&self) -> Size;
+
+    /// Reserves the address space and returns a pointer to its start, or null on failure.
+    ///
+    /// # Safety
+    ///
+    /// This is only called once by the allocator, and the returned pointer must be aligned to at least 32 bytes.
     unsafe fn allocate_address_space(&mut self) -> *mut u8;
+
+    /// Makes sure that the first `size` bytes of the address space starting at `base` are accessible.
+    ///
+    /// # Safety
+    ///
+    /// The `base` must have been returned by [`Env::allocate_address_space`](Env::allocate_address_space).
     unsafe fn expand_memory_until(&mut self, base: *mut u8, size: Size) -> bool;
+
+    /// Releases the address space.
+    ///
+    /// # Safety
+    ///
+    /// The `base` must have been returned by [`Env::allocate_address_space`](Env::allocate_address_space), and must not be used afterwards.
     unsafe fn free_address_space(&mut self, base: *mut u8);
+
+    /// Releases the memory past the first `size` bytes of the address space starting at `base`.
+    ///
+    /// Returns the size of the part at the start of the address space which was left as it was, which can be
+    /// rounded up from `size`; everything past it must read as zeroes once it's made accessible again through
+    /// [`Env::expand_memory_until`](Env::expand_memory_until). Returns `None` if this is not supported.
+    ///
+    /// # Safety
+    ///
+    /// The `base` must have been returned by [`Env::allocate_address_space`](Env::allocate_address_space),
+    /// and the memory past the first `size` bytes must not be in use.
+    #[inline]
+    unsafe fn decommit_memory_after(&mut self, _base: *mut u8, _size: Size) -> Option<Size> {
+        None
+    }
+
+    /// Called by the allocator for every operation it performs.
+    #[cfg(feature = "trace")]
+    #[inline(always)]
+    fn trace(&mut self, _event: crate::TraceEvent) {}
 }
 
 #[repr(align(32))]
@@ -35,6 +87,11 @@ pub struct Array<const SIZE: usize>(pub [u8; SIZE]);
 pub struct ArrayPointer<const SIZE: usize>(*mut Array<SIZE>);
 
 impl<const SIZE: usize> ArrayPointer<SIZE> {
+    /// Creates a new environment which will allocate memory from the given `array`.
+    ///
+    /// # Safety
+    ///
+    /// The `array` must be valid for reads and writes for as long as the allocator which uses it is alive.
     pub const unsafe fn new(array: *mut Array<SIZE>) -> Self {
         ArrayPointer(array)
     }
@@ -56,11 +113,92 @@ impl<const SIZE: usize> Env for ArrayPointer<SIZE> {
     unsafe fn free_address_space(&mut self, _base: *mut u8) {}
 }
 
+/// An environment which allocates memory from a region whose address and size are only known at runtime.
+///
+/// The start of the region is automatically aligned, and the memory is zeroed lazily as the heap grows,
+/// so the region doesn't have to be zeroed beforehand.
+pub struct SliceEnv {
+    base: *mut u8,
+    total_space: Size,
+    zeroed: usize,
+}
+
+impl SliceEnv {
+    /// Creates a new environment which will allocate memory from `length` bytes starting at `pointer`.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid for reads and writes for as long as the allocator which uses it is alive.
+    pub unsafe fn new(pointer: *mut u8, length: usize) -> Self {
+        let padding = pointer.align_offset(32);
+        // The allocator can't address more than 4 GiB.
+        let length = core::cmp::min(length.saturating_sub(padding), u32::MAX as usize) & !31;
+        SliceEnv {
+            base: if length == 0 {
+                core::ptr::null_mut()
+            } else {
+                pointer.wrapping_add(padding)
+            },
+            total_space: Size::from_bytes_usize(length).unwrap_or(Size::from_bytes_usize(0).unwrap()),
+            zeroed: 0,
+        }
+    }
+}
+
+impl Env for SliceEnv {
+    fn total_space(&self) -> Size {
+        self.total_space
+    }
+
+    unsafe fn allocate_address_space(&mut self) -> *mut u8 {
+        self.base
+    }
+
+    unsafe fn expand_memory_until(&mut self, base: *mut u8, size: Size) -> bool {
+        if size > self.total_space {
+            return false;
+        }
+
+        let size = size.bytes() as usize;
+        if size > self.zeroed {
+            unsafe { base.add(self.zeroed).write_bytes(0, size - self.zeroed) };
+            self.zeroed = size;
+        }
+
+        true
+    }
+
+    unsafe fn free_address_space(&mut self, _base: *mut u8) {
+        self.zeroed = 0;
+    }
+}
+
 pub struct System<const SIZE: usize>;
 
-#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[cfg(picoalloc_linux_syscalls)]
 mod linux;
 
+#[cfg(picoalloc_linux_syscalls)]
+pub use linux::LinuxEnv;
+
+#[cfg(all(picoalloc_linux_syscalls, any(feature = "shared_heap", feature = "persistent_heap")))]
+pub use linux::SharedEnv;
+
+#[cfg(all(picoalloc_linux_syscalls, feature = "shared_heap"))]
+pub use linux::SharedHeap;
+
+#[cfg(all(picoalloc_linux_syscalls, feature = "persistent_heap"))]
+pub use linux::{PersistentHeap, PersistentHeapGuard};
+
+#[cfg(all(picoalloc_linux_syscalls, feature = "heap_profiler"))]
+pub(crate) use linux::for_each_executable_mapping;
+
+#[cfg(feature = "std")]
+mod hosted;
+
+#[cfg(feature = "std")]
+pub use hosted::StdEnv;
+
 #[cfg(all(target_env = "polkavm", not(feature = "corevm")))]
 mod polkavm;
 
@@ -0,0 +1,63 @@
+use crate::{Env, Size};
+use std::alloc::{GlobalAlloc, Layout};
+
+/// An environment backed by the system allocator from `std`, which works on any hosted platform.
+///
+/// The whole heap is allocated upfront as a single zeroed block.
+pub struct StdEnv<const SIZE: usize>;
+
+impl<const SIZE: usize> StdEnv<SIZE> {
+    const LAYOUT: Layout = match Layout::from_size_align(SIZE, 32) {
+        Ok(layout) => layout,
+        Err(_) => panic!("invalid heap size"),
+    };
+}
+
+impl<const SIZE: usize> Env for StdEnv<SIZE> {
+    #[inline]
+    fn total_space(&self) -> Size {
+        const { Size::from_bytes_usize(SIZE).unwrap() }
+    }
+
+    #[inline]
+    unsafe fn allocate_address_space(&mut self) -> *mut u8 {
+        if SIZE == 0 {
+            return core::ptr::null_mut();
+        }
+
+        unsafe { std::alloc::System.alloc_zeroed(Self::LAYOUT) }
+    }
+
+    #[inline]
+    unsafe fn expand_memory_until(&mut self, _base: *mut u8, size: Size) -> bool {
+        size <= self.total_space()
+    }
+
+    #[inline]
+    unsafe fn free_address_space(&mut self, base: *mut u8) {
+        unsafe { std::alloc::System.dealloc(base, Self::LAYOUT) }
+    }
+}
+
+#[cfg(not(any(picoalloc_linux_syscalls, target_env = "polkavm", target_family = "wasm")))]
+impl<const SIZE: usize> Env for crate::env::System<SIZE> {
+    #[inline]
+    fn total_space(&self) -> Size {
+        StdEnv::<SIZE>.total_space()
+    }
+
+    #[inline]
+    unsafe fn allocate_address_space(&mut self) -> *mut u8 {
+        StdEnv::<SIZE>.allocate_address_space()
+    }
+
+    #[inline]
+    unsafe fn expand_memory_until(&mut self, base: *mut u8, size: Size) -> bool {
+        StdEnv::<SIZE>.expand_memory_until(base, size)
+    }
+
+    #[inline]
+    unsafe fn free_address_space(&mut self, base: *mut u8) {
+        StdEnv::<SIZE>.free_address_space(base)
+    }
+}
@@ -1,9 +1,99 @@
 use crate::env::{abort, System};
 use crate::{Env, Size};
 
+#[cfg(target_arch = "x86_64")]
+#[allow(dead_code)]
+mod x86_64;
+
+#[cfg(target_arch = "x86_64")]
+use self::x86_64 as arch;
+
+#[cfg(target_arch = "aarch64")]
+#[allow(dead_code)]
+mod aarch64;
+
+#[cfg(target_arch = "aarch64")]
+use self::aarch64 as arch;
+
+#[cfg(target_arch = "riscv64")]
+#[allow(dead_code)]
+mod riscv64;
+
+#[cfg(target_arch = "riscv64")]
+use self::riscv64 as arch;
+
+#[cfg(target_arch = "x86")]
+#[allow(dead_code)]
+mod x86;
+
+#[cfg(target_arch = "x86")]
+use self::x86 as arch;
+
+#[cfg(target_arch = "arm")]
+#[allow(dead_code)]
+mod arm;
+
+#[cfg(target_arch = "arm")]
+use self::arm as arch;
+
+use self::arch::{syscall2, syscall3, syscall6, SYS_MADVISE, SYS_MMAP, SYS_MPROTECT, SYS_MUNMAP};
+
+#[cfg(any(feature = "shared_heap", feature = "persistent_heap", feature = "heap_profiler"))]
+use self::arch::{syscall1, SYS_CLOSE};
+
+#[cfg(feature = "shared_heap")]
+mod shared;
+
+#[cfg(feature = "shared_heap")]
+pub use self::shared::SharedHeap;
+
+#[cfg(feature = "persistent_heap")]
+mod persistent;
+
+#[cfg(feature = "persistent_heap")]
+pub use self::persistent::{PersistentHeap, PersistentHeapGuard};
+
+#[cfg(feature = "heap_profiler")]
+mod maps;
+
+#[cfg(feature = "heap_profiler")]
+pub(crate) use self::maps::for_each_executable_mapping;
+
+const PROT_NONE: usize = 0;
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+#[cfg(any(feature = "shared_heap", feature = "persistent_heap"))]
+const MAP_SHARED: usize = 1;
+const MAP_PRIVATE: usize = 2;
+const MAP_ANONYMOUS: usize = 32;
+const MAP_NORESERVE: usize = 0x4000;
+const MADV_DONTNEED: usize = 4;
+const MADV_HUGEPAGE: usize = 14;
+const MADV_POPULATE_WRITE: usize = 23;
+
+#[cfg(feature = "persistent_heap")]
+const SEEK_END: usize = 2;
+
+/// The size of a transparent huge page.
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// The smallest page size supported by Linux.
+const MIN_PAGE_SIZE: usize = 4096;
+
+/// The size of the inaccessible guard region on each side of the heap.
+///
+/// This is a multiple of every page size supported by Linux, so it also doubles as the granularity
+/// with which the memory is committed.
+const GUARD_SIZE: usize = 64 * 1024;
+
+#[inline]
+fn is_error(result: usize) -> bool {
+    (result as isize) >= -4095 && (result as isize) < 0
+}
+
 #[inline]
 fn abort_on_fail(result: usize) -> usize {
-    if (result as isize) >= -4095 && (result as isize) < 0 {
+    if is_error(result) {
         abort();
     }
 
@@ -11,37 +101,152 @@ fn abort_on_fail(result: usize) -> usize {
 }
 
 #[inline]
-unsafe fn syscall2(nr: usize, a0: usize, a1: usize) -> usize {
-    let r0;
-    core::arch::asm!(
-        "syscall",
-        inlateout("rax") nr => r0,
-        in("rdi") a0,
-        in("rsi") a1,
-        lateout("rcx") _,
-        lateout("r11") _,
-        options(nostack, preserves_flags)
-    );
-    r0
+const fn mapping_size(size: usize) -> usize {
+    size.next_multiple_of(GUARD_SIZE) + GUARD_SIZE * 2
 }
 
+/// Reserves an inaccessible mapping with room for `size` bytes surrounded by guard regions,
+/// and returns a pointer to the start of the heap, aligned to at least `align` bytes.
 #[inline]
-unsafe fn syscall6(nr: usize, a0: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize) -> usize {
-    let r0;
-    core::arch::asm!(
-        "syscall",
-        inlateout("rax") nr => r0,
-        in("rdi") a0,
-        in("rsi") a1,
-        in("rdx") a2,
-        in("r10") a3,
-        in("r8") a4,
-        in("r9") a5,
-        lateout("rcx") _,
-        lateout("r11") _,
-        options(nostack, preserves_flags)
+unsafe fn reserve(size: usize, align: usize, flags: usize) -> Option<*mut u8> {
+    let length = mapping_size(size);
+    let padding = if align > GUARD_SIZE { align } else { 0 };
+    let pointer = syscall6(
+        SYS_MMAP,
+        0,
+        length + padding,
+        PROT_NONE,
+        MAP_ANONYMOUS | MAP_PRIVATE | flags,
+        usize::MAX,
+        0,
     );
-    r0
+
+    if is_error(pointer) {
+        return None;
+    }
+
+    let pointer: *mut u8 = core::ptr::with_exposed_provenance_mut(pointer);
+    if padding == 0 {
+        return Some(pointer.add(GUARD_SIZE));
+    }
+
+    // Trim the excess so that only the aligned mapping remains.
+    let start = pointer.add(GUARD_SIZE).align_offset(align);
+    if start > 0 {
+        abort_on_fail(syscall2(SYS_MUNMAP, pointer.expose_provenance(), start));
+    }
+
+    if padding > start {
+        abort_on_fail(syscall2(
+            SYS_MUNMAP,
+            pointer.add(start + length).expose_provenance(),
+            padding - start,
+        ));
+    }
+
+    Some(pointer.add(start + GUARD_SIZE))
+}
+
+/// Makes `length` bytes starting at `pointer` readable and writable.
+#[inline]
+unsafe fn commit(pointer: *mut u8, length: usize) -> bool {
+    !is_error(syscall3(SYS_MPROTECT, pointer.expose_provenance(), length, PROT_READ | PROT_WRITE))
+}
+
+/// Faults in `length` bytes starting at `pointer`, which must already be committed.
+#[inline]
+unsafe fn populate(pointer: *mut u8, length: usize) {
+    if !is_error(syscall3(SYS_MADVISE, pointer.expose_provenance(), length, MADV_POPULATE_WRITE)) {
+        return;
+    }
+
+    // Older kernels don't support `MADV_POPULATE_WRITE`, so touch every page manually.
+    for offset in (0..length).step_by(MIN_PAGE_SIZE) {
+        pointer.add(offset).write_volatile(0);
+    }
+}
+
+/// Throws away the contents of `length` bytes starting at `pointer`, so that they read as zeroes afterwards.
+#[inline]
+unsafe fn discard(pointer: *mut u8, length: usize) {
+    abort_on_fail(syscall3(SYS_MADVISE, pointer.expose_provenance(), length, MADV_DONTNEED));
+}
+
+/// Unmaps a heap of `size` bytes previously reserved with [`reserve`], along with its guard regions.
+///
+/// Does nothing if `base` is null, which is what an allocator which was never used passes in.
+#[inline]
+unsafe fn release(base: *mut u8, size: usize) {
+    if base.is_null() {
+        return;
+    }
+
+    abort_on_fail(syscall2(SYS_MUNMAP, base.sub(GUARD_SIZE).expose_provenance(), mapping_size(size)));
+}
+
+#[cfg(any(feature = "shared_heap", feature = "persistent_heap"))]
+/// The environment used by the allocator of a heap which lives in a shared file mapping.
+///
+/// The heap is always fully mapped, so this only hands out the address of the heap once, when the heap is created.
+pub struct SharedEnv<const SIZE: usize> {
+    base: *mut u8,
+}
+
+#[cfg(any(feature = "shared_heap", feature = "persistent_heap"))]
+impl<const SIZE: usize> Env for SharedEnv<SIZE> {
+    #[inline]
+    fn total_space(&self) -> Size {
+        const { Size::from_bytes_usize(SIZE).unwrap() }
+    }
+
+    #[inline]
+    unsafe fn allocate_address_space(&mut self) -> *mut u8 {
+        self.base
+    }
+
+    #[inline]
+    unsafe fn expand_memory_until(&mut self, _base: *mut u8, size: Size) -> bool {
+        size <= self.total_space()
+    }
+
+    #[inline]
+    unsafe fn free_address_space(&mut self, _base: *mut u8) {}
+}
+
+/// Maps `length` bytes of the file `fd` with `MAP_SHARED`.
+#[cfg(any(feature = "shared_heap", feature = "persistent_heap"))]
+unsafe fn map(fd: i32, length: usize) -> Option<*mut u8> {
+    let pointer = syscall6(SYS_MMAP, 0, length, PROT_READ | PROT_WRITE, MAP_SHARED, fd as usize, 0);
+    if is_error(pointer) {
+        return None;
+    }
+
+    Some(core::ptr::with_exposed_provenance_mut(pointer))
+}
+
+/// Returns the size of the file `fd`; unlike seeking to its end this doesn't touch the file offset.
+#[cfg(feature = "shared_heap")]
+fn file_size(fd: i32) -> Option<u64> {
+    let mut stat = [0_u64; 18];
+    if is_error(unsafe { syscall2(self::arch::SYS_FSTAT, fd as usize, stat.as_mut_ptr().expose_provenance()) }) {
+        return None;
+    }
+
+    let size = unsafe {
+        stat.as_ptr()
+            .cast::<u8>()
+            .add(self::arch::STAT_SIZE_OFFSET)
+            .cast::<u64>()
+            .read_unaligned()
+    };
+    Some(size)
+}
+
+#[cfg(any(feature = "shared_heap", feature = "persistent_heap", feature = "heap_profiler"))]
+fn close(fd: i32) {
+    unsafe {
+        syscall1(SYS_CLOSE, fd as usize);
+    }
 }
 
 impl<const SIZE: usize> Env for System<SIZE> {
@@ -52,23 +257,13 @@ impl<const SIZE: usize> Env for System<SIZE> {
 
     #[inline]
     unsafe fn allocate_address_space(&mut self) -> *mut u8 {
-        const SYS_MMAP: usize = 9;
-        const PROT_READ: usize = 1;
-        const PROT_WRITE: usize = 2;
-        const MAP_PRIVATE: usize = 2;
-        const MAP_ANONYMOUS: usize = 32;
         unsafe {
-            let pointer = abort_on_fail(syscall6(
-                SYS_MMAP,
-                0,
-                self.total_space().bytes() as usize,
-                PROT_READ | PROT_WRITE,
-                MAP_ANONYMOUS | MAP_PRIVATE,
-                usize::MAX,
-                0,
-            ));
-
-            core::ptr::with_exposed_provenance_mut(pointer)
+            let Some(pointer) = reserve(SIZE, GUARD_SIZE, 0) else { abort() };
+            if !commit(pointer, SIZE) {
+                abort();
+            }
+
+            pointer
         }
     }
 
@@ -79,9 +274,152 @@ impl<const SIZE: usize> Env for System<SIZE> {
 
     #[inline]
     unsafe fn free_address_space(&mut self, base: *mut u8) {
-        const SYS_MUNMAP: usize = 11;
         unsafe {
-            abort_on_fail(syscall2(SYS_MUNMAP, base.expose_provenance(), self.total_space().bytes() as usize));
+            release(base, SIZE);
+        }
+    }
+
+    #[inline]
+    unsafe fn decommit_memory_after(&mut self, base: *mut u8, size: Size) -> Option<Size> {
+        let size = core::cmp::min((size.bytes() as usize).next_multiple_of(GUARD_SIZE), SIZE);
+        if size < SIZE {
+            unsafe { discard(base.add(size), SIZE - size) };
+        }
+
+        Size::from_bytes_usize(size)
+    }
+}
+
+/// A configurable Linux environment which commits the memory incrementally as the heap grows.
+///
+/// The whole heap is reserved upfront, but only the part which the allocator has actually used is accessible;
+/// any access beyond it, or into the guard regions around the heap, faults immediately.
+pub struct LinuxEnv<const SIZE: usize> {
+    committed: usize,
+    huge_pages: bool,
+    no_reserve: bool,
+    prefault: usize,
+}
+
+impl<const SIZE: usize> LinuxEnv<SIZE> {
+    pub const fn new() -> Self {
+        LinuxEnv {
+            committed: 0,
+            huge_pages: false,
+            no_reserve: false,
+            prefault: 0,
         }
     }
+
+    /// Aligns the heap to the huge page size and requests transparent huge pages with `MADV_HUGEPAGE`.
+    pub const fn with_huge_pages(mut self, value: bool) -> Self {
+        self.huge_pages = value;
+        self
+    }
+
+    /// Maps the heap with `MAP_NORESERVE`, so that no swap space is reserved for it.
+    pub const fn with_no_reserve(mut self, value: bool) -> Self {
+        self.no_reserve = value;
+        self
+    }
+
+    /// Commits and faults in the first `bytes` of the heap when the address space is allocated.
+    pub const fn with_prefault(mut self, bytes: usize) -> Self {
+        self.prefault = bytes;
+        self
+    }
+
+    /// Returns how many bytes of the heap are currently accessible.
+    pub fn committed_space(&self) -> usize {
+        self.committed
+    }
+}
+
+impl<const SIZE: usize> Default for LinuxEnv<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> Env for LinuxEnv<SIZE> {
+    #[inline]
+    fn total_space(&self) -> Size {
+        const { Size::from_bytes_usize(SIZE).unwrap() }
+    }
+
+    #[inline]
+    unsafe fn allocate_address_space(&mut self) -> *mut u8 {
+        let align = if self.huge_pages { HUGE_PAGE_SIZE } else { GUARD_SIZE };
+        let flags = if self.no_reserve { MAP_NORESERVE } else { 0 };
+        let Some(pointer) = (unsafe { reserve(SIZE, align, flags) }) else {
+            return core::ptr::null_mut();
+        };
+
+        if self.huge_pages {
+            // This is only a hint, so failures (e.g. when THP is disabled) are fine.
+            unsafe { syscall3(SYS_MADVISE, pointer.expose_provenance(), SIZE, MADV_HUGEPAGE) };
+        }
+
+        // Clamped first, so that rounding up a huge value can't overflow.
+        let prefault = core::cmp::min(core::cmp::min(self.prefault, SIZE).next_multiple_of(GUARD_SIZE), SIZE);
+        if prefault > 0 {
+            unsafe {
+                if !commit(pointer, prefault) {
+                    release(pointer, SIZE);
+                    return core::ptr::null_mut();
+                }
+
+                populate(pointer, prefault);
+            }
+
+            self.committed = prefault;
+        }
+
+        pointer
+    }
+
+    #[inline]
+    unsafe fn expand_memory_until(&mut self, base: *mut u8, size: Size) -> bool {
+        let size = size.bytes() as usize;
+        if size <= self.committed {
+            return true;
+        }
+
+        let new_committed = core::cmp::min(size.next_multiple_of(GUARD_SIZE), SIZE);
+        if !unsafe { commit(base.add(self.committed), new_committed - self.committed) } {
+            return false;
+        }
+
+        self.committed = new_committed;
+        true
+    }
+
+    #[inline]
+    unsafe fn free_address_space(&mut self, base: *mut u8) {
+        unsafe {
+            release(base, SIZE);
+        }
+
+        self.committed = 0;
+    }
+
+    #[inline]
+    unsafe fn decommit_memory_after(&mut self, base: *mut u8, size: Size) -> Option<Size> {
+        let size = core::cmp::min((size.bytes() as usize).next_multiple_of(GUARD_SIZE), SIZE);
+        if size < self.committed {
+            unsafe {
+                discard(base.add(size), self.committed - size);
+                abort_on_fail(syscall3(
+                    SYS_MPROTECT,
+                    base.add(size).expose_provenance(),
+                    self.committed - size,
+                    PROT_NONE,
+                ));
+            }
+
+            self.committed = size;
+        }
+
+        Size::from_bytes_usize(size)
+    }
 }
@@ -0,0 +1,73 @@
+pub const SYS_MMAP: usize = 222;
+pub const SYS_MPROTECT: usize = 226;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_MADVISE: usize = 233;
+pub const SYS_MEMFD_CREATE: usize = 279;
+pub const SYS_FTRUNCATE: usize = 46;
+pub const SYS_LSEEK: usize = 62;
+pub const SYS_DUP: usize = 23;
+pub const SYS_CLOSE: usize = 57;
+pub const SYS_READ: usize = 63;
+pub const SYS_OPENAT: usize = 56;
+pub const SYS_FLOCK: usize = 32;
+pub const SYS_MSYNC: usize = 227;
+pub const SYS_FSTAT: usize = 80;
+
+/// The offset of `st_size` in the structure filled by `SYS_FSTAT`.
+pub const STAT_SIZE_OFFSET: usize = 48;
+
+#[inline]
+pub unsafe fn syscall1(nr: usize, a0: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "svc #0",
+        in("x8") nr,
+        inlateout("x0") a0 => r0,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall2(nr: usize, a0: usize, a1: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "svc #0",
+        in("x8") nr,
+        inlateout("x0") a0 => r0,
+        in("x1") a1,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall3(nr: usize, a0: usize, a1: usize, a2: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "svc #0",
+        in("x8") nr,
+        inlateout("x0") a0 => r0,
+        in("x1") a1,
+        in("x2") a2,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall6(nr: usize, a0: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "svc #0",
+        in("x8") nr,
+        inlateout("x0") a0 => r0,
+        in("x1") a1,
+        in("x2") a2,
+        in("x3") a3,
+        in("x4") a4,
+        in("x5") a5,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
@@ -0,0 +1,74 @@
+pub const SYS_MMAP: usize = 192; // mmap2
+pub const SYS_MPROTECT: usize = 125;
+pub const SYS_MUNMAP: usize = 91;
+pub const SYS_MADVISE: usize = 220;
+pub const SYS_MEMFD_CREATE: usize = 385;
+pub const SYS_FTRUNCATE: usize = 93;
+pub const SYS_LSEEK: usize = 19;
+pub const SYS_DUP: usize = 41;
+pub const SYS_CLOSE: usize = 6;
+pub const SYS_READ: usize = 3;
+pub const SYS_OPENAT: usize = 322;
+pub const SYS_FLOCK: usize = 143;
+pub const SYS_MSYNC: usize = 144;
+pub const SYS_FSTAT: usize = 197; // fstat64
+
+/// The offset of `st_size` in the structure filled by `SYS_FSTAT`.
+pub const STAT_SIZE_OFFSET: usize = 48;
+
+// In Thumb mode `r7` is the frame pointer and can't be used as an operand,
+// so the syscall number has to be swapped into it manually.
+
+#[cfg(not(target_feature = "thumb-mode"))]
+macro_rules! syscall {
+    ($nr:expr, $a0:expr, $($operands:tt)*) => {{
+        let r0;
+        core::arch::asm!(
+            "svc 0",
+            in("r7") $nr,
+            inlateout("r0") $a0 => r0,
+            $($operands)*
+            options(nostack, preserves_flags)
+        );
+        r0
+    }};
+}
+
+#[cfg(target_feature = "thumb-mode")]
+macro_rules! syscall {
+    ($nr:expr, $a0:expr, $($operands:tt)*) => {{
+        let r0;
+        core::arch::asm!(
+            "mov {saved_r7}, r7",
+            "mov r7, {nr}",
+            "svc 0",
+            "mov r7, {saved_r7}",
+            nr = in(reg) $nr,
+            saved_r7 = out(reg) _,
+            inlateout("r0") $a0 => r0,
+            $($operands)*
+            options(nostack, preserves_flags)
+        );
+        r0
+    }};
+}
+
+#[inline]
+pub unsafe fn syscall1(nr: usize, a0: usize) -> usize {
+    syscall!(nr, a0,)
+}
+
+#[inline]
+pub unsafe fn syscall2(nr: usize, a0: usize, a1: usize) -> usize {
+    syscall!(nr, a0, in("r1") a1,)
+}
+
+#[inline]
+pub unsafe fn syscall3(nr: usize, a0: usize, a1: usize, a2: usize) -> usize {
+    syscall!(nr, a0, in("r1") a1, in("r2") a2,)
+}
+
+#[inline]
+pub unsafe fn syscall6(nr: usize, a0: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize) -> usize {
+    syscall!(nr, a0, in("r1") a1, in("r2") a2, in("r3") a3, in("r4") a4, in("r5") a5,)
+}
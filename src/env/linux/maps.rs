@@ -0,0 +1,109 @@
+use super::arch::{syscall3, syscall6, SYS_OPENAT, SYS_READ};
+use super::{close, is_error};
+
+const AT_FDCWD: usize = -100_isize as usize;
+const O_RDONLY: usize = 0;
+const O_CLOEXEC: usize = 0o2000000;
+
+/// Lines longer than this are skipped; they can only be mappings of files with extremely long paths.
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// Calls `callback` with the start, the end, the file offset and the path of every executable file mapping of the current process.
+///
+/// Does nothing if `/proc/self/maps` can't be read.
+pub(crate) fn for_each_executable_mapping(mut callback: impl FnMut(u64, u64, u64, &[u8])) {
+    let fd = unsafe {
+        syscall6(
+            SYS_OPENAT,
+            AT_FDCWD,
+            c"/proc/self/maps".as_ptr().expose_provenance(),
+            O_RDONLY | O_CLOEXEC,
+            0,
+            0,
+            0,
+        )
+    };
+    if is_error(fd) {
+        return;
+    }
+
+    let fd = fd as i32;
+    let mut buffer = [0; MAX_LINE_LENGTH];
+    let mut length = 0;
+    let mut is_skipping_line = false;
+    loop {
+        if let Some(end) = buffer[..length].iter().position(|&byte| byte == b'\n') {
+            if !is_skipping_line {
+                parse_line(&buffer[..end], &mut callback);
+            }
+
+            is_skipping_line = false;
+            buffer.copy_within(end + 1..length, 0);
+            length -= end + 1;
+            continue;
+        }
+
+        if length == buffer.len() {
+            is_skipping_line = true;
+            length = 0;
+        }
+
+        let count = unsafe {
+            syscall3(
+                SYS_READ,
+                fd as usize,
+                buffer[length..].as_mut_ptr().expose_provenance(),
+                buffer.len() - length,
+            )
+        };
+        if is_error(count) || count == 0 {
+            break;
+        }
+
+        length += count;
+    }
+
+    close(fd);
+}
+
+/// Parses a line of `/proc/self/maps`, e.g. `55d0c0a00000-55d0c0a2f000 r-xp 00002000 fd:01 1234 /usr/bin/foo`.
+fn parse_line(line: &[u8], callback: &mut impl FnMut(u64, u64, u64, &[u8])) {
+    let mut fields = line.split(|&byte| byte == b' ').filter(|field| !field.is_empty());
+    let (Some(range), Some(permissions), Some(offset)) = (fields.next(), fields.next(), fields.next()) else {
+        return;
+    };
+
+    // Skip the device and the inode; whatever follows them is the path, which can contain spaces.
+    let (Some(_), Some(inode)) = (fields.next(), fields.next()) else {
+        return;
+    };
+
+    let path_start = inode.as_ptr().addr() - line.as_ptr().addr() + inode.len();
+    let path = line[path_start..].trim_ascii_start();
+    if permissions.get(2) != Some(&b'x') || !path.starts_with(b"/") {
+        return;
+    }
+
+    let Some(separator) = range.iter().position(|&byte| byte == b'-') else {
+        return;
+    };
+
+    if let (Some(start), Some(end), Some(offset)) = (
+        parse_hex(&range[..separator]),
+        parse_hex(&range[separator + 1..]),
+        parse_hex(offset),
+    ) {
+        callback(start, end, offset, path);
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+
+    digits.iter().try_fold(0, |value, &digit| {
+        let digit = (digit as char).to_digit(16)?;
+        Some(value << 4 | u64::from(digit))
+    })
+}
@@ -0,0 +1,332 @@
+use super::arch::{syscall2, syscall3, syscall6, SYS_FLOCK, SYS_FTRUNCATE, SYS_LSEEK, SYS_MSYNC, SYS_MUNMAP, SYS_OPENAT};
+use super::{abort_on_fail, close, is_error, map, SharedEnv, GUARD_SIZE, SEEK_END};
+use crate::mutex::MutexGuard;
+use crate::{Allocator, FitStrategy, Mutex, Size};
+use core::ffi::CStr;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+const AT_FDCWD: usize = -100_isize as usize;
+const O_RDWR: usize = 0o2;
+const O_CREAT: usize = 0o100;
+const O_CLOEXEC: usize = 0o2000000;
+const LOCK_EX: usize = 2;
+const LOCK_NB: usize = 4;
+const MS_SYNC: usize = 4;
+
+/// The magic bytes which every persistent heap starts with.
+const PERSISTENT_HEAP_MAGIC: [u8; 8] = *b"picoprs1";
+
+/// The version of the persistent heap's layout; bumped on every incompatible change.
+const PERSISTENT_HEAP_VERSION: u32 = 2;
+
+/// Marks an unset root pointer.
+const NULL_ROOT: u64 = u64::MAX;
+
+/// Marks an unset budget.
+const NO_BUDGET: u64 = u64::MAX;
+
+/// The header at the start of the file; the heap itself starts right after it.
+///
+/// Only the allocator's settings are stored here; its free lists are rebuilt from the chunk headers
+/// every time the file is opened.
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    header_size: u32,
+    heap_size: u64,
+    root: AtomicU64,
+    /// The allocated space in bytes, as of the last time the allocator was unlocked.
+    allocated_space: AtomicU64,
+    budget: AtomicU64,
+    fit_strategy: AtomicU32,
+    fit_strategy_parameter: AtomicU32,
+    /// Set while the allocator is locked; if it's set when the file is opened the process
+    /// which last used the heap has died in the middle of an allocator operation.
+    dirty: AtomicBool,
+}
+
+impl Header {
+    fn store_state<E: crate::Env>(&self, allocator: &Allocator<E>) {
+        let (fit_strategy, fit_strategy_parameter) = match allocator.fit_strategy() {
+            FitStrategy::FirstInBin => (0, 0),
+            FitStrategy::BoundedSearch(limit) => (1, limit),
+            FitStrategy::BestFit => (2, 0),
+            FitStrategy::LowestAddress => (3, 0),
+        };
+
+        let budget = allocator.budget().map_or(NO_BUDGET, |budget| u64::from(budget.bytes()));
+        self.allocated_space
+            .store(u64::from(allocator.allocated_space().bytes()), Ordering::Relaxed);
+        self.budget.store(budget, Ordering::Relaxed);
+        self.fit_strategy.store(fit_strategy, Ordering::Relaxed);
+        self.fit_strategy_parameter.store(fit_strategy_parameter, Ordering::Relaxed);
+    }
+
+    fn fit_strategy(&self) -> Option<FitStrategy> {
+        Some(match self.fit_strategy.load(Ordering::Relaxed) {
+            0 => FitStrategy::FirstInBin,
+            1 => FitStrategy::BoundedSearch(self.fit_strategy_parameter.load(Ordering::Relaxed)),
+            2 => FitStrategy::BestFit,
+            3 => FitStrategy::LowestAddress,
+            _ => return None,
+        })
+    }
+}
+
+fn size_from_u64(bytes: u64) -> Option<Size> {
+    Size::from_bytes_usize(usize::try_from(bytes).ok()?)
+}
+
+/// A heap which lives in a regular file mapped with `MAP_SHARED`, so that it survives the process which has created it.
+///
+/// The file is locked with `flock`, so only a single [`PersistentHeap`] can have it open at a time. The free lists
+/// are rebuilt from the chunk headers every time the file is opened, which also recovers the heap if the process
+/// has died while the allocator was locked; if the chunk headers themselves were left inconsistent the heap fails to open.
+///
+/// The data is only guaranteed to reach the disk after [`PersistentHeap::flush`] is called.
+pub struct PersistentHeap<const SIZE: usize> {
+    mapping: *mut u8,
+    fd: i32,
+    allocator: Mutex<Allocator<SharedEnv<SIZE>>>,
+}
+
+unsafe impl<const SIZE: usize> Send for PersistentHeap<SIZE> {}
+unsafe impl<const SIZE: usize> Sync for PersistentHeap<SIZE> {}
+
+impl<const SIZE: usize> PersistentHeap<SIZE> {
+    const HEAP_OFFSET: usize = core::mem::size_of::<Header>().next_multiple_of(GUARD_SIZE);
+    const MAPPING_SIZE: usize = Self::HEAP_OFFSET + SIZE.next_multiple_of(GUARD_SIZE);
+
+    /// Opens the persistent heap at `path`, creating it if the file doesn't exist or is empty.
+    ///
+    /// Returns `None` if the file can't be opened, is already opened by another [`PersistentHeap`],
+    /// doesn't contain a persistent heap of this size, or can't be recovered.
+    pub fn open(path: &CStr) -> Option<Self> {
+        let fd = unsafe {
+            syscall6(
+                SYS_OPENAT,
+                AT_FDCWD,
+                path.as_ptr().expose_provenance(),
+                O_RDWR | O_CREAT | O_CLOEXEC,
+                0o600,
+                0,
+                0,
+            )
+        };
+        if is_error(fd) {
+            return None;
+        }
+
+        let fd = fd as i32;
+        if is_error(unsafe { syscall2(SYS_FLOCK, fd as usize, LOCK_EX | LOCK_NB) }) {
+            close(fd);
+            return None;
+        }
+
+        let length = unsafe { syscall3(SYS_LSEEK, fd as usize, 0, SEEK_END) };
+        let is_new = length == 0;
+        if !is_new && length != Self::MAPPING_SIZE {
+            close(fd);
+            return None;
+        }
+
+        if is_new && is_error(unsafe { syscall2(SYS_FTRUNCATE, fd as usize, Self::MAPPING_SIZE) }) {
+            close(fd);
+            return None;
+        }
+
+        let Some(mapping) = (unsafe { map(fd, Self::MAPPING_SIZE) }) else {
+            close(fd);
+            return None;
+        };
+
+        let heap = PersistentHeap {
+            mapping,
+            fd,
+            allocator: Mutex::new(Allocator::new(SharedEnv {
+                base: mapping.wrapping_add(Self::HEAP_OFFSET),
+            })),
+        };
+
+        let is_ok = unsafe {
+            if is_new {
+                heap.create()
+            } else {
+                heap.reopen()
+            }
+        };
+
+        if !is_ok {
+            return None;
+        }
+
+        Some(heap)
+    }
+
+    unsafe fn create(&self) -> bool {
+        self.header_pointer().write(Header {
+            magic: PERSISTENT_HEAP_MAGIC,
+            version: PERSISTENT_HEAP_VERSION,
+            header_size: core::mem::size_of::<Header>() as u32,
+            heap_size: SIZE as u64,
+            root: AtomicU64::new(NULL_ROOT),
+            allocated_space: AtomicU64::new(0),
+            budget: AtomicU64::new(NO_BUDGET),
+            fit_strategy: AtomicU32::new(0),
+            fit_strategy_parameter: AtomicU32::new(0),
+            dirty: AtomicBool::new(false),
+        });
+
+        let mut allocator = self.allocator.lock();
+        if !allocator.initialize_now() {
+            return false;
+        }
+
+        self.header().store_state(&allocator);
+        true
+    }
+
+    unsafe fn reopen(&self) -> bool {
+        let header = self.header_pointer();
+        let is_valid = (*header).magic == PERSISTENT_HEAP_MAGIC
+            && (*header).version == PERSISTENT_HEAP_VERSION
+            && (*header).header_size == core::mem::size_of::<Header>() as u32
+            && (*header).heap_size == SIZE as u64;
+        if !is_valid {
+            return false;
+        }
+
+        let header = self.header();
+        let Some(fit_strategy) = header.fit_strategy() else {
+            return false;
+        };
+
+        let budget = match header.budget.load(Ordering::Relaxed) {
+            NO_BUDGET => None,
+            budget => match size_from_u64(budget) {
+                Some(budget) => Some(budget),
+                None => return false,
+            },
+        };
+
+        // A process which died in the middle of an operation could have used more space than what was stored.
+        let allocated_space = if header.dirty.load(Ordering::Acquire) {
+            Some(const { Size::from_bytes_usize(SIZE).unwrap() })
+        } else {
+            size_from_u64(header.allocated_space.load(Ordering::Relaxed))
+        };
+
+        let Some(allocated_space) = allocated_space else {
+            return false;
+        };
+
+        let mut allocator = self.allocator.lock();
+        allocator.set_budget(budget);
+        allocator.set_fit_strategy(fit_strategy);
+        if !allocator.recover(self.heap_base(), allocated_space) {
+            return false;
+        }
+
+        header.store_state(&allocator);
+        header.dirty.store(false, Ordering::Release);
+        true
+    }
+
+    /// Locks the heap and returns its allocator.
+    pub fn lock(&self) -> PersistentHeapGuard<SIZE> {
+        let allocator = self.allocator.lock();
+        let header = self.header();
+        header.dirty.store(true, Ordering::Release);
+        PersistentHeapGuard { allocator, header }
+    }
+
+    /// Returns the root pointer, or `None` if it wasn't set.
+    pub fn root(&self) -> Option<NonNull<u8>> {
+        let offset = self.header().root.load(Ordering::Acquire);
+        if offset == NULL_ROOT {
+            return None;
+        }
+
+        NonNull::new(self.pointer_at(offset as usize))
+    }
+
+    /// Sets the root pointer, which is stored in the file so that the data can be found again after it's reopened.
+    pub fn set_root(&self, pointer: Option<NonNull<u8>>) {
+        let offset = pointer.map_or(NULL_ROOT, |pointer| self.offset_of(pointer) as u64);
+        self.header().root.store(offset, Ordering::Release);
+    }
+
+    /// Returns the offset of `pointer` from the start of the heap.
+    pub fn offset_of(&self, pointer: NonNull<u8>) -> usize {
+        pointer.as_ptr().addr().wrapping_sub(self.heap_base().addr())
+    }
+
+    /// Returns a pointer to the given offset from the start of the heap.
+    pub fn pointer_at(&self, offset: usize) -> *mut u8 {
+        self.heap_base().wrapping_add(offset)
+    }
+
+    /// Synchronously writes the whole heap to the disk.
+    pub fn flush(&self) -> bool {
+        !is_error(unsafe { syscall3(SYS_MSYNC, self.mapping.expose_provenance(), Self::MAPPING_SIZE, MS_SYNC) })
+    }
+
+    #[inline]
+    fn header_pointer(&self) -> *mut Header {
+        self.mapping.cast()
+    }
+
+    #[inline]
+    fn header(&self) -> &Header {
+        unsafe { &*self.header_pointer() }
+    }
+
+    #[inline]
+    fn heap_base(&self) -> *mut u8 {
+        self.mapping.wrapping_add(Self::HEAP_OFFSET)
+    }
+}
+
+impl<const SIZE: usize> Drop for PersistentHeap<SIZE> {
+    fn drop(&mut self) {
+        unsafe {
+            abort_on_fail(syscall2(SYS_MUNMAP, self.mapping.expose_provenance(), Self::MAPPING_SIZE));
+        }
+
+        // This also releases the lock on the file.
+        close(self.fd);
+    }
+}
+
+/// The locked allocator of a [`PersistentHeap`].
+pub struct PersistentHeapGuard<'a, const SIZE: usize> {
+    allocator: MutexGuard<'a, Allocator<SharedEnv<SIZE>>>,
+    header: &'a Header,
+}
+
+impl<const SIZE: usize> Drop for PersistentHeapGuard<'_, SIZE> {
+    #[inline]
+    fn drop(&mut self) {
+        self.header.store_state(&self.allocator);
+        self.header.dirty.store(false, Ordering::Release);
+    }
+}
+
+impl<const SIZE: usize> Deref for PersistentHeapGuard<'_, SIZE> {
+    type Target = Allocator<SharedEnv<SIZE>>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.allocator
+    }
+}
+
+impl<const SIZE: usize> DerefMut for PersistentHeapGuard<'_, SIZE> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.allocator
+    }
+}
@@ -0,0 +1,165 @@
+use super::arch::{syscall1, syscall2, SYS_DUP, SYS_FTRUNCATE, SYS_MEMFD_CREATE, SYS_MUNMAP};
+use super::{abort_on_fail, close, file_size, is_error, map, SharedEnv, GUARD_SIZE};
+use crate::mutex::MutexGuard;
+use crate::{Allocator, Mutex};
+use core::ptr::NonNull;
+
+/// The magic bytes which every shared heap starts with.
+const SHARED_HEAP_MAGIC: [u8; 8] = *b"picoshm1";
+
+/// The version of the shared heap's layout; bumped on every incompatible change.
+const SHARED_HEAP_VERSION: u32 = 1;
+
+/// The header at the start of the shared mapping; the heap itself starts right after it.
+#[repr(C)]
+struct Header<const SIZE: usize> {
+    magic: [u8; 8],
+    version: u32,
+    heap_size: u64,
+    allocator: Mutex<Allocator<SharedEnv<SIZE>>>,
+}
+
+/// A heap which lives in a `memfd` mapped with `MAP_SHARED`, so that it can be used by multiple processes at the same time.
+///
+/// The whole allocator state is stored inside of the shared mapping and protected by a spin lock, and since the
+/// heap is relocatable every process can map it at a different address. Pointers must not be passed between
+/// processes as-is; use [`SharedHeap::offset_of`] and [`SharedHeap::pointer_at`] to convert them to and from offsets.
+///
+/// If a process dies while holding the lock the heap will stay locked forever.
+pub struct SharedHeap<const SIZE: usize> {
+    mapping: *mut u8,
+    fd: i32,
+}
+
+unsafe impl<const SIZE: usize> Send for SharedHeap<SIZE> {}
+unsafe impl<const SIZE: usize> Sync for SharedHeap<SIZE> {}
+
+impl<const SIZE: usize> SharedHeap<SIZE> {
+    const HEAP_OFFSET: usize = core::mem::size_of::<Header<SIZE>>().next_multiple_of(GUARD_SIZE);
+    const MAPPING_SIZE: usize = Self::HEAP_OFFSET + SIZE.next_multiple_of(GUARD_SIZE);
+
+    /// Creates a new shared heap backed by an anonymous `memfd`.
+    ///
+    /// The file descriptor is not close-on-exec, so it can be inherited by child processes.
+    pub fn create() -> Option<Self> {
+        let fd = unsafe { syscall2(SYS_MEMFD_CREATE, c"picoalloc".as_ptr().expose_provenance(), 0) };
+        if is_error(fd) {
+            return None;
+        }
+
+        let fd = fd as i32;
+        if is_error(unsafe { syscall2(SYS_FTRUNCATE, fd as usize, Self::MAPPING_SIZE) }) {
+            close(fd);
+            return None;
+        }
+
+        let Some(mapping) = (unsafe { map(fd, Self::MAPPING_SIZE) }) else {
+            close(fd);
+            return None;
+        };
+
+        let heap = SharedHeap { mapping, fd };
+        unsafe {
+            heap.header_pointer().write(Header {
+                magic: SHARED_HEAP_MAGIC,
+                version: SHARED_HEAP_VERSION,
+                heap_size: SIZE as u64,
+                allocator: Mutex::new(Allocator::new(SharedEnv { base: heap.heap_base() })),
+            });
+        }
+
+        // Initialize the heap right away, so that the address space is never allocated from another process.
+        if !heap.header().allocator.lock().initialize_now() {
+            return None;
+        }
+
+        Some(heap)
+    }
+
+    /// Maps a shared heap created by [`SharedHeap::create`], possibly in another process.
+    ///
+    /// The file descriptor is duplicated, so the caller retains the ownership of `fd`.
+    /// Returns `None` if the file doesn't contain a shared heap of this size.
+    ///
+    /// # Safety
+    ///
+    /// The `fd` must not be modified by anything else than a [`SharedHeap`].
+    pub unsafe fn open(fd: i32) -> Option<Self> {
+        let fd = syscall1(SYS_DUP, fd as usize);
+        if is_error(fd) {
+            return None;
+        }
+
+        let fd = fd as i32;
+        // The duplicated descriptor shares the file offset with `fd`, so the size mustn't be checked by seeking.
+        if file_size(fd) != Some(Self::MAPPING_SIZE as u64) {
+            close(fd);
+            return None;
+        }
+
+        let Some(mapping) = map(fd, Self::MAPPING_SIZE) else {
+            close(fd);
+            return None;
+        };
+
+        let heap = SharedHeap { mapping, fd };
+        let header = heap.header_pointer();
+        let is_valid =
+            (*header).magic == SHARED_HEAP_MAGIC && (*header).version == SHARED_HEAP_VERSION && (*header).heap_size == SIZE as u64;
+        if !is_valid {
+            return None;
+        }
+
+        Some(heap)
+    }
+
+    /// Returns the file descriptor of the underlying `memfd`.
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// Locks the heap and returns its allocator.
+    ///
+    /// Out-of-memory handlers are only valid in the process which has set them, so they're cleared every time the heap is locked.
+    pub fn lock(&self) -> MutexGuard<Allocator<SharedEnv<SIZE>>> {
+        let mut allocator = self.header().allocator.lock();
+        unsafe { allocator.relocate(self.heap_base()) };
+        allocator.set_out_of_memory_handler(None);
+        allocator
+    }
+
+    /// Returns the offset of `pointer` from the start of the heap in this process.
+    pub fn offset_of(&self, pointer: NonNull<u8>) -> usize {
+        pointer.as_ptr().addr().wrapping_sub(self.heap_base().addr())
+    }
+
+    /// Returns a pointer to the given offset from the start of the heap in this process.
+    pub fn pointer_at(&self, offset: usize) -> *mut u8 {
+        self.heap_base().wrapping_add(offset)
+    }
+
+    #[inline]
+    fn header_pointer(&self) -> *mut Header<SIZE> {
+        self.mapping.cast()
+    }
+
+    #[inline]
+    fn header(&self) -> &Header<SIZE> {
+        unsafe { &*self.header_pointer() }
+    }
+
+    #[inline]
+    fn heap_base(&self) -> *mut u8 {
+        self.mapping.wrapping_add(Self::HEAP_OFFSET)
+    }
+}
+
+impl<const SIZE: usize> Drop for SharedHeap<SIZE> {
+    fn drop(&mut self) {
+        unsafe {
+            abort_on_fail(syscall2(SYS_MUNMAP, self.mapping.expose_provenance(), Self::MAPPING_SIZE));
+        }
+
+        close(self.fd);
+    }
+}
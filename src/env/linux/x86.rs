@@ -0,0 +1,81 @@
+pub const SYS_MMAP: usize = 192; // mmap2
+pub const SYS_MPROTECT: usize = 125;
+pub const SYS_MUNMAP: usize = 91;
+pub const SYS_MADVISE: usize = 219;
+pub const SYS_MEMFD_CREATE: usize = 356;
+pub const SYS_FTRUNCATE: usize = 93;
+pub const SYS_LSEEK: usize = 19;
+pub const SYS_DUP: usize = 41;
+pub const SYS_CLOSE: usize = 6;
+pub const SYS_READ: usize = 3;
+pub const SYS_OPENAT: usize = 295;
+pub const SYS_FLOCK: usize = 143;
+pub const SYS_MSYNC: usize = 144;
+pub const SYS_FSTAT: usize = 197; // fstat64
+
+/// The offset of `st_size` in the structure filled by `SYS_FSTAT`.
+pub const STAT_SIZE_OFFSET: usize = 44;
+
+#[inline]
+pub unsafe fn syscall1(nr: usize, a0: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "int 0x80",
+        inlateout("eax") nr => r0,
+        in("ebx") a0,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall2(nr: usize, a0: usize, a1: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "int 0x80",
+        inlateout("eax") nr => r0,
+        in("ebx") a0,
+        in("ecx") a1,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall3(nr: usize, a0: usize, a1: usize, a2: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "int 0x80",
+        inlateout("eax") nr => r0,
+        in("ebx") a0,
+        in("ecx") a1,
+        in("edx") a2,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall6(nr: usize, a0: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize) -> usize {
+    // Both `esi` and `ebp` are reserved by LLVM, so they have to be saved manually
+    // and loaded from memory along with the syscall number.
+    let args = [a3, a5, nr];
+    let r0;
+    core::arch::asm!(
+        "push ebp",
+        "push esi",
+        "mov esi, [eax]",
+        "mov ebp, [eax + 4]",
+        "mov eax, [eax + 8]",
+        "int 0x80",
+        "pop esi",
+        "pop ebp",
+        inlateout("eax") args.as_ptr() => r0,
+        in("ebx") a0,
+        in("ecx") a1,
+        in("edx") a2,
+        in("edi") a4,
+        options(preserves_flags)
+    );
+    r0
+}
@@ -0,0 +1,81 @@
+pub const SYS_MMAP: usize = 9;
+pub const SYS_MPROTECT: usize = 10;
+pub const SYS_MUNMAP: usize = 11;
+pub const SYS_MADVISE: usize = 28;
+pub const SYS_MEMFD_CREATE: usize = 319;
+pub const SYS_FTRUNCATE: usize = 77;
+pub const SYS_LSEEK: usize = 8;
+pub const SYS_DUP: usize = 32;
+pub const SYS_CLOSE: usize = 3;
+pub const SYS_READ: usize = 0;
+pub const SYS_OPENAT: usize = 257;
+pub const SYS_FLOCK: usize = 73;
+pub const SYS_MSYNC: usize = 26;
+pub const SYS_FSTAT: usize = 5;
+
+/// The offset of `st_size` in the structure filled by `SYS_FSTAT`.
+pub const STAT_SIZE_OFFSET: usize = 48;
+
+#[inline]
+pub unsafe fn syscall1(nr: usize, a0: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "syscall",
+        inlateout("rax") nr => r0,
+        in("rdi") a0,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall2(nr: usize, a0: usize, a1: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "syscall",
+        inlateout("rax") nr => r0,
+        in("rdi") a0,
+        in("rsi") a1,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall3(nr: usize, a0: usize, a1: usize, a2: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "syscall",
+        inlateout("rax") nr => r0,
+        in("rdi") a0,
+        in("rsi") a1,
+        in("rdx") a2,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
+
+#[inline]
+pub unsafe fn syscall6(nr: usize, a0: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize) -> usize {
+    let r0;
+    core::arch::asm!(
+        "syscall",
+        inlateout("rax") nr => r0,
+        in("rdi") a0,
+        in("rsi") a1,
+        in("rdx") a2,
+        in("r10") a3,
+        in("r8") a4,
+        in("r9") a5,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack, preserves_flags)
+    );
+    r0
+}
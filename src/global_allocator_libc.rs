@@ -1,9 +1,11 @@
-use crate::allocator::Size;
+use crate::allocator::{AllocError, Size};
 use crate::GLOBAL_ALLOCATOR;
 
 use core::ffi::{c_int, c_void};
 use core::ptr::NonNull;
 
+#[cfg(feature = "heap_profiler")]
+const EINTR: c_int = 4;
 const ENOMEM: c_int = 12;
 const EINVAL: c_int = 22;
 
@@ -11,6 +13,12 @@ extern "C" {
     fn __errno_location() -> *mut c_int;
 }
 
+#[cfg(feature = "heap_profiler")]
+#[inline]
+fn errno() -> c_int {
+    unsafe { *__errno_location() }
+}
+
 #[inline]
 fn set_errno(value: c_int) {
     unsafe {
@@ -18,6 +26,14 @@ fn set_errno(value: c_int) {
     }
 }
 
+#[inline]
+fn error_to_errno(error: AllocError) -> c_int {
+    match error {
+        AllocError::InvalidAlignment => EINVAL,
+        _ => ENOMEM,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn __libc_malloc(size: usize) -> *mut c_void {
     malloc(size)
@@ -45,21 +61,26 @@ pub extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
         return core::ptr::null_mut();
     };
 
-    let Some(total_size) = Size::from_bytes_usize(total_size) else {
+    let Some(size) = Size::from_bytes_usize(total_size) else {
         set_errno(ENOMEM);
         return core::ptr::null_mut();
     };
 
     let pointer = {
         let mut allocator = GLOBAL_ALLOCATOR.lock();
-        allocator.alloc_zeroed(const { Size::from_bytes_usize(16).unwrap() }, total_size)
+        let pointer = allocator.try_alloc_zeroed(const { Size::from_bytes_usize(16).unwrap() }, size);
+        if let Ok(pointer) = pointer {
+            unsafe { crate::SystemAllocator::set_requested_bytes(pointer, total_size) };
+        }
+        pointer
     };
 
-    if let Some(pointer) = pointer {
-        pointer.as_ptr().cast()
-    } else {
-        set_errno(ENOMEM);
-        core::ptr::null_mut()
+    match pointer {
+        Ok(pointer) => pointer.as_ptr().cast(),
+        Err(error) => {
+            set_errno(error_to_errno(error));
+            core::ptr::null_mut()
+        }
     }
 }
 
@@ -109,7 +130,7 @@ pub unsafe extern "C" fn posix_memalign(result: *mut *mut c_void, align: usize,
         return EINVAL;
     }
 
-    let Some(size) = Size::from_bytes_usize(size) else {
+    let Some(size_in_granules) = Size::from_bytes_usize(size) else {
         return ENOMEM;
     };
 
@@ -118,12 +139,16 @@ pub unsafe extern "C" fn posix_memalign(result: *mut *mut c_void, align: usize,
     };
 
     let mut allocator = GLOBAL_ALLOCATOR.lock();
-    if let Some(pointer) = allocator.alloc(align, size) {
-        unsafe { *result = pointer.as_ptr().cast() }
+    match allocator.try_alloc(align, size_in_granules) {
+        Ok(pointer) => {
+            unsafe {
+                crate::SystemAllocator::set_requested_bytes(pointer, size);
+                *result = pointer.as_ptr().cast();
+            }
 
-        0
-    } else {
-        ENOMEM
+            0
+        }
+        Err(error) => error_to_errno(error),
     }
 }
 
@@ -138,17 +163,21 @@ pub unsafe extern "C" fn realloc(pointer: *mut c_void, size: usize) -> *mut c_vo
         return core::ptr::null_mut();
     }
 
-    let Some(size) = Size::from_bytes_usize(size) else {
+    let Some(size_in_granules) = Size::from_bytes_usize(size) else {
         set_errno(ENOMEM);
         return core::ptr::null_mut();
     };
 
     let mut allocator = GLOBAL_ALLOCATOR.lock();
-    if let Some(pointer) = allocator.realloc(pointer.cast::<u8>(), const { Size::from_bytes_usize(1).unwrap() }, size) {
-        pointer.as_ptr().cast()
-    } else {
-        set_errno(ENOMEM);
-        core::ptr::null_mut()
+    match allocator.try_realloc(pointer.cast::<u8>(), const { Size::from_bytes_usize(1).unwrap() }, size_in_granules) {
+        Ok(pointer) => {
+            crate::SystemAllocator::set_requested_bytes(pointer, size);
+            pointer.as_ptr().cast()
+        }
+        Err(error) => {
+            set_errno(error_to_errno(error));
+            core::ptr::null_mut()
+        }
     }
 }
 
@@ -159,3 +188,96 @@ pub unsafe extern "C" fn malloc_usable_size(pointer: *mut c_void) -> usize {
     };
     crate::SystemAllocator::usable_size(pointer.cast())
 }
+
+#[cfg(feature = "heap_profiler")]
+mod heap_profiler {
+    use crate::GLOBAL_ALLOCATOR;
+    use core::ffi::{c_int, c_void};
+    use core::sync::atomic::{AtomicI32, Ordering};
+
+    extern "C" {
+        fn write(fd: c_int, buffer: *const c_void, count: usize) -> isize;
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    const SIG_ERR: usize = usize::MAX;
+
+    static SIGNAL_OUTPUT_FD: AtomicI32 = AtomicI32::new(-1);
+
+    fn write_all(fd: c_int, mut bytes: &[u8]) -> bool {
+        while !bytes.is_empty() {
+            let count = unsafe { write(fd, bytes.as_ptr().cast(), bytes.len()) };
+            if count < 0 {
+                if super::errno() == super::EINTR {
+                    continue;
+                }
+
+                return false;
+            }
+
+            bytes = &bytes[count as usize..];
+        }
+
+        true
+    }
+
+    fn write_profile(allocator: &crate::SystemAllocator, fd: c_int) -> bool {
+        let mut is_ok = true;
+        allocator.env().write_pprof(&mut |bytes: &[u8]| {
+            is_ok = is_ok && write_all(fd, bytes);
+        });
+
+        is_ok
+    }
+
+    extern "C" fn on_signal(_signum: c_int) {
+        let fd = SIGNAL_OUTPUT_FD.load(Ordering::Relaxed);
+        if fd < 0 {
+            return;
+        }
+
+        // The signal could have interrupted the allocator, so never wait for the lock here.
+        if let Some(allocator) = GLOBAL_ALLOCATOR.try_lock() {
+            write_profile(&allocator, fd);
+        }
+    }
+
+    /// Starts sampling the allocations; the heap profiler is disabled until this is called.
+    ///
+    /// # Safety
+    ///
+    /// The whole program, including every shared library which calls `malloc`, must be compiled with frame pointers.
+    #[no_mangle]
+    pub unsafe extern "C" fn picoalloc_heap_profile_enable() {
+        crate::enable_global_heap_profiler();
+    }
+
+    /// Writes the heap profile in the pprof protobuf format into `fd`.
+    ///
+    /// Returns zero on success, or -1 if writing failed.
+    #[no_mangle]
+    pub extern "C" fn picoalloc_heap_profile_write(fd: c_int) -> c_int {
+        let allocator = GLOBAL_ALLOCATOR.lock();
+        if write_profile(&allocator, fd) {
+            0
+        } else {
+            -1
+        }
+    }
+
+    /// Makes the heap profile be written into `fd` every time the signal `signum` is received.
+    ///
+    /// If the allocator is busy when the signal arrives then no profile is written.
+    ///
+    /// Returns zero on success, or -1 if the signal handler couldn't be installed.
+    #[no_mangle]
+    pub extern "C" fn picoalloc_heap_profile_install_signal_handler(signum: c_int, fd: c_int) -> c_int {
+        SIGNAL_OUTPUT_FD.store(fd, Ordering::Relaxed);
+        let handler: extern "C" fn(c_int) = on_signal;
+        if unsafe { signal(signum, handler as usize) } == SIG_ERR {
+            -1
+        } else {
+            0
+        }
+    }
+}
@@ -1,28 +1,120 @@
 #![no_std]
 #![allow(unexpected_cfgs)]
 
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
 mod allocator;
 mod env;
 
+#[cfg(feature = "trace")]
+mod trace;
+
+#[cfg(feature = "heap_profiler")]
+mod profiler;
+
 #[cfg(target_has_atomic = "8")]
 mod mutex;
 
 #[cfg(feature = "global_allocator_libc")]
 mod global_allocator_libc;
 
-#[cfg(any(feature = "global_allocator_rust", feature = "global_allocator_libc"))]
+#[cfg(all(
+    any(feature = "global_allocator_rust", feature = "global_allocator_libc"),
+    not(feature = "heap_profiler")
+))]
 pub(crate) type SystemAllocator = Allocator<crate::env::System<{ 1024 * 1024 * 1024 }>>;
 
-#[cfg(any(feature = "global_allocator_rust", feature = "global_allocator_libc"))]
+#[cfg(all(
+    any(feature = "global_allocator_rust", feature = "global_allocator_libc"),
+    not(feature = "heap_profiler")
+))]
 #[cfg_attr(feature = "global_allocator_rust", global_allocator)]
 pub(crate) static GLOBAL_ALLOCATOR: Mutex<SystemAllocator> = Mutex::new(SystemAllocator::new(crate::env::System));
 
-pub use crate::allocator::{Allocator, Size};
-pub use crate::env::{Array, ArrayPointer, Env};
+#[cfg(all(
+    any(feature = "global_allocator_rust", feature = "global_allocator_libc"),
+    feature = "heap_profiler"
+))]
+pub(crate) type SystemAllocator = Allocator<Profiled<crate::env::System<{ 1024 * 1024 * 1024 }>, FramePointerUnwinder>>;
+
+#[cfg(all(
+    any(feature = "global_allocator_rust", feature = "global_allocator_libc"),
+    feature = "heap_profiler"
+))]
+#[cfg_attr(feature = "global_allocator_rust", global_allocator)]
+pub(crate) static GLOBAL_ALLOCATOR: Mutex<SystemAllocator> = Mutex::new(SystemAllocator::new(
+    // SAFETY: The unwinder is only used after `enable_global_heap_profiler` is called, which is `unsafe`.
+    Profiled::new(crate::env::System, unsafe { FramePointerUnwinder::new() }).with_enabled(false),
+));
+
+/// Starts sampling the allocations made through the global allocator.
+///
+/// The heap profiler is disabled until this is called.
+///
+/// # Safety
+///
+/// The whole program, including any C code which allocates through the global allocator, must be compiled
+/// with frame pointers enabled (e.g. with `-C force-frame-pointers=yes` and `-fno-omit-frame-pointer`).
+#[cfg(all(
+    any(feature = "global_allocator_rust", feature = "global_allocator_libc"),
+    feature = "heap_profiler"
+))]
+pub unsafe fn enable_global_heap_profiler() {
+    GLOBAL_ALLOCATOR.lock().env_mut().set_enabled(true);
+}
+
+/// Writes the heap profile of the global allocator in the pprof protobuf format.
+///
+/// The global allocator is locked while the profile is written, so `output` must not allocate memory.
+#[cfg(all(
+    any(feature = "global_allocator_rust", feature = "global_allocator_libc"),
+    feature = "heap_profiler"
+))]
+pub fn write_global_heap_profile(output: &mut impl TraceSink) {
+    GLOBAL_ALLOCATOR.lock().env().write_pprof(output);
+}
+
+/// Sets the callback which is called when the global allocator runs out of memory.
+///
+/// The handler is called with the global allocator locked, so it must release memory through the allocator it receives.
+#[cfg(any(feature = "global_allocator_rust", feature = "global_allocator_libc"))]
+pub fn set_global_out_of_memory_handler(handler: Option<fn(&mut SystemAllocator, &OutOfMemory) -> bool>) {
+    GLOBAL_ALLOCATOR.lock().set_out_of_memory_handler(handler);
+}
+
+pub use crate::allocator::{
+    AllocError, AllocInfo, Allocator, BinStats, FitStrategy, Fragmentation, FreeBins, OutOfMemory, OutOfMemoryHandler, Size, SnapshotError,
+};
+pub use crate::env::{Array, ArrayPointer, Env, SliceEnv};
+
+#[cfg(feature = "trace")]
+pub use crate::trace::{TraceEvent, TraceSink, Traced, TRACE_MAGIC};
+
+#[cfg(feature = "heap_profiler")]
+pub use crate::profiler::{FramePointerUnwinder, Profiled, StackUnwinder, MAX_FRAMES};
+
+#[cfg(picoalloc_linux_syscalls)]
+pub use crate::env::LinuxEnv;
+
+#[cfg(all(picoalloc_linux_syscalls, any(feature = "shared_heap", feature = "persistent_heap")))]
+pub use crate::env::SharedEnv;
+
+#[cfg(all(picoalloc_linux_syscalls, feature = "shared_heap"))]
+pub use crate::env::SharedHeap;
+
+#[cfg(all(picoalloc_linux_syscalls, feature = "persistent_heap"))]
+pub use crate::env::{PersistentHeap, PersistentHeapGuard};
+
+#[cfg(feature = "std")]
+pub use crate::env::StdEnv;
 
 #[cfg(target_has_atomic = "8")]
 pub use crate::mutex::Mutex;
 
+#[cfg(feature = "lock_api")]
+pub use crate::allocator::LockApiAllocator;
+
 #[doc(hidden)]
 pub use crate::env::abort;
 
@@ -82,12 +174,18 @@ fn test_allocator<E: Env>(env: E) {
     }
 }
 
-#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[cfg(picoalloc_linux_syscalls)]
 #[test]
 fn test_allocator_system() {
     test_allocator(crate::env::System::<4096>);
 }
 
+#[cfg(feature = "std")]
+#[test]
+fn test_allocator_std() {
+    test_allocator(StdEnv::<4096>);
+}
+
 #[test]
 fn test_allocator_buffer() {
     let mut buffer = Array([0_u8; 4096]);
@@ -123,12 +221,120 @@ fn test_many_small_allocations<E: Env>(env: E, count: usize) {
     }
 }
 
-#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[cfg(picoalloc_linux_syscalls)]
 #[test]
 fn test_many_small_allocations_native() {
     test_many_small_allocations(crate::env::System::<{ 32 * 1024 * 1024 }>, 524288);
 }
 
+#[cfg(picoalloc_linux_syscalls)]
+#[test]
+fn test_many_small_allocations_linux_env() {
+    test_many_small_allocations(LinuxEnv::<{ 32 * 1024 * 1024 }>::new(), 524288);
+}
+
+#[cfg(picoalloc_linux_syscalls)]
+#[test]
+fn test_linux_env_commit_on_demand() {
+    let mut allocator = Allocator::new(LinuxEnv::<{ 32 * 1024 * 1024 }>::new());
+    assert_eq!(allocator.env().committed_space(), 0);
+
+    let a = allocator
+        .alloc(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(100).unwrap())
+        .unwrap();
+    assert_eq!(allocator.env().committed_space(), 64 * 1024);
+
+    let b = allocator
+        .alloc(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(1024 * 1024).unwrap())
+        .unwrap();
+    assert_eq!(allocator.env().committed_space(), 1024 * 1024 + 64 * 1024);
+    unsafe {
+        b.as_ptr().add(1024 * 1024 - 1).write(1);
+        allocator.free(a);
+        allocator.free(b);
+    }
+}
+
+#[cfg(picoalloc_linux_syscalls)]
+#[test]
+fn test_linux_env_reset_and_decommit() {
+    let one = Size::from_bytes_usize(1).unwrap();
+    let megabyte = Size::from_bytes_usize(1024 * 1024).unwrap();
+    let mut allocator = Allocator::new(LinuxEnv::<{ 32 * 1024 * 1024 }>::new());
+    for _ in 0..4 {
+        let pointer = allocator.alloc(one, megabyte).unwrap();
+        unsafe { pointer.as_ptr().write_bytes(0xff, 1024 * 1024) };
+    }
+
+    assert_eq!(allocator.env().committed_space(), 4 * 1024 * 1024 + 64 * 1024);
+    unsafe { allocator.reset_and_decommit(Size::from_bytes_usize(100 * 1024).unwrap()) };
+    assert_eq!(allocator.env().committed_space(), 128 * 1024);
+    assert_eq!(allocator.allocated_space(), Size::from_bytes_usize(128 * 1024).unwrap());
+    assert_eq!(allocator.used_space(), Size::from_bytes_usize(0).unwrap());
+
+    // The decommitted memory is zeroed when it's committed again.
+    let pointer = allocator
+        .alloc_zeroed(one, Size::from_bytes_usize(2 * 1024 * 1024).unwrap())
+        .unwrap();
+    assert!(unsafe { core::slice::from_raw_parts(pointer.as_ptr(), 2 * 1024 * 1024) }
+        .iter()
+        .all(|&byte| byte == 0));
+}
+
+/// Returns the permissions of the mapping which contains `address`, according to `/proc/self/maps`.
+#[cfg(picoalloc_linux_syscalls)]
+#[cfg(test)]
+fn mapping_permissions(address: usize) -> Option<std::string::String> {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    maps.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (start, end) = fields.next()?.split_once('-')?;
+        let range = usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?;
+        range.contains(&address).then(|| fields.next().unwrap().into())
+    })
+}
+
+#[cfg(picoalloc_linux_syscalls)]
+#[test]
+fn test_linux_guard_regions() {
+    const SIZE: usize = 32 * 1024 * 1024;
+    const GUARD_SIZE: usize = 64 * 1024;
+
+    fn check<E: Env>(env: E) {
+        let mut allocator = Allocator::new(env);
+        let pointer = allocator.alloc(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(100).unwrap());
+        assert!(pointer.is_some());
+
+        let base = allocator.base_address().addr();
+        assert_eq!(mapping_permissions(base).as_deref(), Some("rw-p"));
+        for address in [base - GUARD_SIZE, base - 1, base + SIZE, base + SIZE + GUARD_SIZE - 1] {
+            assert_eq!(mapping_permissions(address).as_deref(), Some("---p"));
+        }
+    }
+
+    check(crate::env::System::<SIZE>);
+    check(LinuxEnv::<SIZE>::new());
+}
+
+#[cfg(picoalloc_linux_syscalls)]
+#[test]
+fn test_linux_drop_unused() {
+    drop(Allocator::new(crate::env::System::<{ 32 * 1024 * 1024 }>));
+    drop(Allocator::new(LinuxEnv::<{ 32 * 1024 * 1024 }>::new()));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_many_small_allocations_std() {
+    test_many_small_allocations(StdEnv::<{ 32 * 1024 * 1024 }>, 524288);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_std_drop_unused() {
+    drop(Allocator::new(StdEnv::<{ 32 * 1024 * 1024 }>));
+}
+
 #[test]
 fn test_many_small_allocations_buffer() {
     #[repr(C)]
@@ -218,9 +424,10 @@ fn test_shrink() {
     unsafe { alloc.free(a) };
     unsafe { alloc.free(b) };
 
+    // The single granule left after the allocation can't hold any data, so it's absorbed.
     let a = alloc.alloc(one, two).unwrap();
-    assert_eq!(unsafe { Allocator::<ArrayPointer<128>>::usable_size(a) }, 64);
-    assert!(alloc.alloc(one, one).is_none());
+    assert_eq!(unsafe { Allocator::<ArrayPointer<128>>::usable_size(a) }, 96);
+    assert!(alloc.alloc(one, Size::from_bytes_usize(0).unwrap()).is_none());
     unsafe {
         alloc.shrink_inplace(a, one);
     }
@@ -248,3 +455,1375 @@ fn test_grow() {
     assert_eq!(unsafe { alloc.grow_inplace(a, two) }, Some(two));
     assert_eq!(unsafe { Allocator::<ArrayPointer<128>>::usable_size(a) }, 64);
 }
+
+#[cfg(feature = "realloc_inplace")]
+#[test]
+fn test_realloc_backward() {
+    let one = Size::from_bytes_usize(32).unwrap();
+    let two = Size::from_bytes_usize(64).unwrap();
+    let three = Size::from_bytes_usize(96).unwrap();
+    let five = Size::from_bytes_usize(160).unwrap();
+
+    let mut buffer = Array([0_u8; 512]);
+    let mut alloc = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+
+    // Backward.
+    let a = alloc.alloc(one, one).unwrap();
+    let b = alloc.alloc(one, one).unwrap();
+    let c = alloc.alloc(one, one).unwrap();
+    unsafe {
+        b.as_ptr().write_bytes(0xaa, 32);
+        alloc.free(a);
+    }
+
+    let b = unsafe { alloc.realloc(b, one, two) }.unwrap();
+    assert_eq!(b, a);
+    assert_eq!(unsafe { core::slice::from_raw_parts(b.as_ptr(), 32) }, [0xaa; 32]);
+    assert_eq!(alloc.used_space(), three);
+
+    // Bidirectional.
+    let d = alloc.alloc(one, one).unwrap();
+    let e = alloc.alloc(one, one).unwrap();
+    let f = alloc.alloc(one, one).unwrap();
+    unsafe {
+        d.as_ptr().write_bytes(0xbb, 32);
+        alloc.free(c);
+        alloc.free(e);
+    }
+
+    // The space left over after growing `b` is merged together with `c`.
+    let d = unsafe { alloc.realloc(d, one, five) }.unwrap();
+    assert_eq!(d.as_ptr(), unsafe { c.as_ptr().sub(32) });
+    assert_eq!(unsafe { core::slice::from_raw_parts(d.as_ptr(), 32) }, [0xbb; 32]);
+
+    // The alignment is respected.
+    unsafe {
+        alloc.free(b);
+        alloc.free(d);
+        alloc.free(f);
+    }
+
+    let a = alloc.alloc(one, one).unwrap();
+    let b = alloc.alloc(one, one).unwrap();
+    let c = alloc.alloc(one, one).unwrap();
+    let d = alloc.alloc(one, one).unwrap();
+    unsafe {
+        alloc.free(a);
+        alloc.free(b);
+    }
+
+    let old_c = c;
+    let c = unsafe { alloc.realloc(c, two, three) }.unwrap();
+    assert_eq!(c.as_ptr().addr() % 64, 0);
+    assert!(c.as_ptr() < old_c.as_ptr());
+    assert!(unsafe { Allocator::<ArrayPointer<512>>::usable_size(c) } >= 96);
+
+    unsafe {
+        alloc.free(c);
+        alloc.free(d);
+    }
+    assert_eq!(alloc.used_space(), Size::from_bytes_usize(0).unwrap());
+}
+
+#[cfg(feature = "trace")]
+#[test]
+fn test_trace() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    let one = Size::from_bytes_usize(32).unwrap();
+    let two = Size::from_bytes_usize(64).unwrap();
+
+    let mut buffer = Array([0_u8; 256]);
+    let mut trace = Vec::new();
+    {
+        let env = Traced::new(unsafe { ArrayPointer::new(&mut buffer) }, |bytes: &[u8]| {
+            trace.extend_from_slice(bytes)
+        });
+        let mut alloc = Allocator::new(env);
+        let a = alloc.alloc(one, one).unwrap();
+        let b = alloc.alloc_zeroed(one, one).unwrap();
+        assert!(unsafe { alloc.grow_inplace(a, two) }.is_none());
+        unsafe { alloc.free(b) };
+        assert_eq!(unsafe { alloc.grow_inplace(a, two) }, Some(two));
+        unsafe { alloc.shrink_inplace(a, one) };
+        let a = unsafe { alloc.realloc(a, one, two) }.unwrap();
+        unsafe { alloc.free(a) };
+        assert!(alloc.alloc(one, Size::from_bytes_usize(1024).unwrap()).is_none());
+        unsafe { alloc.reset() };
+    }
+
+    assert!(trace.starts_with(&TRACE_MAGIC));
+
+    let mut events = Vec::new();
+    let mut bytes = &trace[TRACE_MAGIC.len()..];
+    while !bytes.is_empty() {
+        let (event, length) = TraceEvent::decode(bytes).unwrap();
+        events.push(event);
+        bytes = &bytes[length..];
+    }
+
+    let kilobyte = Size::from_bytes_usize(1024).unwrap();
+    assert_eq!(
+        events,
+        [
+            TraceEvent::Alloc {
+                align: one,
+                size: one,
+                zeroed: false,
+                result: Some(1)
+            },
+            TraceEvent::Alloc {
+                align: one,
+                size: one,
+                zeroed: true,
+                result: Some(3)
+            },
+            TraceEvent::Grow {
+                handle: 1,
+                size: two,
+                result: None
+            },
+            TraceEvent::Free { handle: 3 },
+            TraceEvent::Grow {
+                handle: 1,
+                size: two,
+                result: Some(two)
+            },
+            TraceEvent::Shrink { handle: 1, size: one },
+            TraceEvent::Realloc {
+                handle: 1,
+                align: one,
+                size: two,
+                result: Some(1)
+            },
+            TraceEvent::Free { handle: 1 },
+            TraceEvent::Alloc {
+                align: one,
+                size: kilobyte,
+                zeroed: false,
+                result: None
+            },
+            TraceEvent::Reset,
+        ]
+    );
+}
+
+#[cfg(all(picoalloc_linux_syscalls, feature = "heap_profiler"))]
+#[test]
+fn test_heap_profiler() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CALLER: AtomicUsize = AtomicUsize::new(0);
+
+    struct TestUnwinder;
+    impl StackUnwinder for TestUnwinder {
+        fn unwind(&mut self, frames: &mut [usize]) -> usize {
+            let caller = CALLER.load(Ordering::Relaxed);
+            frames[0] = 0x1000 + caller;
+            frames[1] = test_heap_profiler as usize + 1;
+            2
+        }
+    }
+
+    fn read_varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_fields(mut bytes: &[u8]) -> Vec<(u64, &[u8])> {
+        let mut fields = Vec::new();
+        while !bytes.is_empty() {
+            let key = read_varint(&mut bytes);
+            if key & 7 == 2 {
+                let length = read_varint(&mut bytes) as usize;
+                fields.push((key >> 3, &bytes[..length]));
+                bytes = &bytes[length..];
+            } else {
+                assert_eq!(key & 7, 0);
+                let start = bytes;
+                read_varint(&mut bytes);
+                fields.push((key >> 3, &start[..start.len() - bytes.len()]));
+            }
+        }
+        fields
+    }
+
+    let mut buffer = Array([0_u8; 4096]);
+    let env = Profiled::new(unsafe { ArrayPointer::new(&mut buffer) }, TestUnwinder).with_sample_interval(1);
+    let mut alloc = Allocator::new(env);
+
+    let size_a = Size::from_bytes_usize(64).unwrap();
+    let size_b = Size::from_bytes_usize(32).unwrap();
+    let a0 = alloc.alloc(size_b, size_a).unwrap();
+    let a1 = alloc.alloc(size_b, size_a).unwrap();
+    CALLER.store(1, Ordering::Relaxed);
+    let b0 = alloc.alloc(size_b, size_b).unwrap();
+    CALLER.store(0, Ordering::Relaxed);
+    let a2 = unsafe { alloc.realloc(a1, size_b, Size::from_bytes_usize(96).unwrap()) }.unwrap();
+    unsafe {
+        alloc.free(a0);
+    }
+
+    // Allocations made while the profiler is disabled aren't sampled.
+    alloc.env_mut().set_enabled(false);
+    CALLER.store(2, Ordering::Relaxed);
+    let c0 = alloc.alloc(size_b, size_b).unwrap();
+    CALLER.store(0, Ordering::Relaxed);
+    alloc.env_mut().set_enabled(true);
+
+    let mut profile = Vec::new();
+    alloc.env().write_pprof(&mut |bytes: &[u8]| profile.extend_from_slice(bytes));
+
+    let fields = read_fields(&profile);
+    let strings: Vec<&[u8]> = fields.iter().filter(|field| field.0 == 6).map(|field| field.1).collect();
+    assert_eq!(strings[0], b"");
+    assert!(strings.contains(&&b"inuse_space"[..]));
+    assert_eq!(fields.iter().filter(|field| field.0 == 1).count(), 4);
+
+    let read_message = |message: &[u8]| -> Vec<(u64, u64)> {
+        read_fields(message)
+            .into_iter()
+            .map(|(field, mut value)| (field, read_varint(&mut value)))
+            .collect()
+    };
+
+    // The executable mappings are included, so that the addresses can be symbolized.
+    let mappings: Vec<Vec<(u64, u64)>> = fields
+        .iter()
+        .filter(|field| field.0 == 3)
+        .map(|field| read_message(field.1))
+        .collect();
+    let address = test_heap_profiler as usize as u64;
+    let mapping = mappings
+        .iter()
+        .find(|mapping| (mapping[1].1..mapping[2].1).contains(&address))
+        .unwrap();
+    let executable = std::env::current_exe().unwrap();
+    assert_eq!(strings[mapping[4].1 as usize], executable.as_os_str().as_encoded_bytes());
+
+    let locations: Vec<(u64, u64)> = fields
+        .iter()
+        .filter(|field| field.0 == 4)
+        .map(|field| {
+            let location = read_message(field.1);
+            let mapping_id = location.iter().find(|(field, _)| *field == 2).map(|(_, id)| *id);
+            let location_address = location.iter().find(|(field, _)| *field == 3).unwrap().1;
+            if location_address == address {
+                assert_eq!(mapping_id, Some(mapping[0].1));
+            } else {
+                assert_eq!(mapping_id, None);
+            }
+            (location[0].1, location_address)
+        })
+        .collect();
+
+    let mut samples: Vec<(u64, Vec<u64>)> = fields
+        .iter()
+        .filter(|field| field.0 == 2)
+        .map(|field| {
+            let sample = read_fields(field.1);
+            let mut location_ids = sample[0].1;
+            let first_location = read_varint(&mut location_ids);
+            let address = locations.iter().find(|location| location.0 == first_location).unwrap().1;
+
+            let mut values = Vec::new();
+            let mut bytes = sample[1].1;
+            while !bytes.is_empty() {
+                values.push(read_varint(&mut bytes));
+            }
+            (address, values)
+        })
+        .collect();
+    samples.sort();
+
+    assert_eq!(samples, [(0x0fff, alloc::vec![3, 224, 1, 96]), (0x1000, alloc::vec![1, 32, 1, 32])]);
+
+    unsafe {
+        alloc.free(a2);
+        alloc.free(b0);
+        alloc.free(c0);
+    }
+}
+
+#[test]
+fn test_fragmentation() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    let one = Size::from_bytes_usize(32).unwrap();
+    let two = Size::from_bytes_usize(64).unwrap();
+
+    let mut buffer = Array([0_u8; 4096]);
+    let mut alloc = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+    assert_eq!(alloc.fragmentation(), Fragmentation::default());
+
+    let pointers: Vec<_> = (0..5).map(|_| alloc.alloc(one, two).unwrap()).collect();
+    unsafe {
+        alloc.free(pointers[1]);
+        alloc.free(pointers[3]);
+    }
+
+    let bins: Vec<_> = alloc.free_bins().collect();
+    assert_eq!(
+        bins,
+        [
+            BinStats {
+                index: 2,
+                min_chunk_size: Size::from_bytes_usize(96).unwrap(),
+                free_chunks: 2,
+                free_space: 192
+            },
+            BinStats {
+                index: 112,
+                min_chunk_size: Size::from_bytes_usize(3616).unwrap(),
+                free_chunks: 1,
+                free_space: 3616
+            }
+        ]
+    );
+
+    let fragmentation = alloc.fragmentation();
+    assert_eq!(
+        fragmentation,
+        Fragmentation {
+            allocated_chunks: 3,
+            allocated_space: 192,
+            free_chunks: 3,
+            free_space: 3808,
+            uncommitted_free_space: 3584,
+            largest_free_chunk: 3616,
+            header_overhead: 96,
+            rounding_waste: 0,
+            padding_waste: 0,
+        }
+    );
+    assert!((fragmentation.external_fragmentation() - 192.0 / 3808.0).abs() < 1e-9);
+
+    unsafe {
+        alloc.free(pointers[0]);
+        alloc.free(pointers[2]);
+        alloc.free(pointers[4]);
+    }
+
+    assert_eq!(alloc.fragmentation().free_chunks, 1);
+    assert_eq!(alloc.fragmentation().external_fragmentation(), 0.0);
+}
+
+#[test]
+fn test_fragmentation_waste() {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[repr(align(4096))]
+    struct AlignedArray(Array<4096>);
+
+    let one = Size::from_bytes_usize(32).unwrap();
+    let two = Size::from_bytes_usize(64).unwrap();
+
+    let mut buffer = AlignedArray(Array([0_u8; 4096]));
+    let alloc = Mutex::new(Allocator::new(unsafe { ArrayPointer::new(&mut buffer.0) }));
+
+    // Aligning the second allocation leaves a free chunk with no room for data in front of it.
+    let a = alloc.lock().alloc(one, one).unwrap();
+    let b = alloc.lock().alloc(two, one).unwrap();
+    assert_eq!(b.as_ptr().addr() - a.as_ptr().addr(), 96);
+
+    // The bytes which were requested are only known to `GlobalAlloc`.
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let c = unsafe { alloc.alloc(layout) };
+    let fragmentation = alloc.lock().fragmentation();
+    assert_eq!(fragmentation.rounding_waste, 24);
+    assert_eq!(fragmentation.padding_waste, 32);
+
+    let c = unsafe { alloc.realloc(c, layout, 70) };
+    assert_eq!(alloc.lock().fragmentation().rounding_waste, 26);
+
+    unsafe {
+        alloc.dealloc(c, Layout::from_size_align(70, 8).unwrap());
+        alloc.lock().free(a);
+        alloc.lock().free(b);
+    }
+
+    let fragmentation = alloc.lock().fragmentation();
+    assert_eq!(fragmentation.rounding_waste, 0);
+    assert_eq!(fragmentation.padding_waste, 0);
+}
+
+#[test]
+fn test_alloc_errors() {
+    struct RefusingEnv {
+        buffer: Array<4096>,
+        is_initialized: bool,
+        limit: Size,
+    }
+
+    impl Env for RefusingEnv {
+        fn total_space(&self) -> Size {
+            const { Size::from_bytes_usize(4096).unwrap() }
+        }
+
+        unsafe fn allocate_address_space(&mut self) -> *mut u8 {
+            if self.is_initialized {
+                self.buffer.0.as_mut_ptr()
+            } else {
+                core::ptr::null_mut()
+            }
+        }
+
+        unsafe fn expand_memory_until(&mut self, _base: *mut u8, size: Size) -> bool {
+            size <= self.limit
+        }
+
+        unsafe fn free_address_space(&mut self, _base: *mut u8) {}
+    }
+
+    let one = Size::from_bytes_usize(32).unwrap();
+    let two = Size::from_bytes_usize(64).unwrap();
+
+    let mut alloc = Allocator::new(RefusingEnv {
+        buffer: Array([0; 4096]),
+        is_initialized: false,
+        limit: Size::from_bytes_usize(128).unwrap(),
+    });
+
+    assert_eq!(alloc.try_alloc(one, one), Err(AllocError::InitializationFailed));
+    alloc.env_mut().is_initialized = true;
+
+    assert_eq!(
+        alloc.try_alloc(Size::from_bytes_usize(96).unwrap(), one),
+        Err(AllocError::InvalidAlignment)
+    );
+    assert_eq!(
+        alloc.try_alloc(one, Size::from_bytes_usize(1024 * 1024 * 1024).unwrap()),
+        Err(AllocError::SizeOverflow)
+    );
+    assert_eq!(
+        alloc.try_alloc(one, Size::from_bytes_usize(8192).unwrap()),
+        Err(AllocError::OutOfSpace)
+    );
+    assert_eq!(
+        alloc.try_alloc(one, Size::from_bytes_usize(256).unwrap()),
+        Err(AllocError::ExpansionRefused)
+    );
+    assert!(!AllocError::InvalidAlignment.is_out_of_memory());
+    assert!(AllocError::ExpansionRefused.is_out_of_memory());
+
+    let a = alloc.try_alloc(one, one).unwrap();
+    assert_eq!(
+        unsafe { alloc.try_grow_inplace(a, Size::from_bytes_usize(128).unwrap()) },
+        Err(AllocError::ExpansionRefused)
+    );
+    assert_eq!(unsafe { alloc.try_grow_inplace(a, two) }, Ok(two));
+
+    // Reallocating to a zero size shrinks the allocation instead of freeing it.
+    assert_eq!(unsafe { alloc.try_realloc(a, one, Size::from_bytes_usize(0).unwrap()) }, Ok(a));
+    assert_eq!(unsafe { Allocator::<RefusingEnv>::usable_size(a) }, 0);
+    assert_eq!(
+        unsafe { alloc.try_realloc(a, one, Size::from_bytes_usize(1024).unwrap()) },
+        Err(AllocError::ExpansionRefused)
+    );
+    unsafe { alloc.free(a) };
+}
+
+#[test]
+fn test_out_of_memory_handler() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CACHE: AtomicUsize = AtomicUsize::new(0);
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn handler(allocator: &mut Allocator<ArrayPointer<4096>>, info: &OutOfMemory) -> bool {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(info.size, Size::from_bytes_usize(2048).unwrap());
+        assert_eq!(info.error, AllocError::OutOfSpace);
+        assert_eq!(info.used_space, allocator.used_space());
+
+        // Allocations done from within the handler must not call it again.
+        assert!(allocator.alloc(Size::from_bytes_usize(1).unwrap(), info.size).is_none());
+
+        let Some(cached) = core::ptr::NonNull::new(CACHE.swap(0, Ordering::Relaxed) as *mut u8) else {
+            return false;
+        };
+
+        unsafe { allocator.free(cached) };
+        true
+    }
+
+    let one = Size::from_bytes_usize(1).unwrap();
+    let half = Size::from_bytes_usize(2048).unwrap();
+
+    let mut buffer = Array([0_u8; 4096]);
+    let mut alloc = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+    alloc.set_out_of_memory_handler(Some(handler));
+
+    let cached = alloc.alloc(one, half).unwrap();
+    CACHE.store(cached.as_ptr() as usize, Ordering::Relaxed);
+    assert_eq!(alloc.used_space(), half);
+
+    let a = alloc.alloc(one, half).unwrap();
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(alloc.used_space(), half);
+
+    assert_eq!(alloc.try_alloc(one, half), Err(AllocError::OutOfSpace));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+
+    unsafe {
+        alloc.shrink_inplace(a, Size::from_bytes_usize(64).unwrap());
+        assert_eq!(alloc.used_space(), Size::from_bytes_usize(64).unwrap());
+        alloc.free(a);
+    }
+    assert_eq!(alloc.used_space(), Size::from_bytes_usize(0).unwrap());
+
+    // A handler can remove itself.
+    static ONE_SHOT_CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn one_shot_handler(allocator: &mut Allocator<ArrayPointer<4096>>, _info: &OutOfMemory) -> bool {
+        ONE_SHOT_CALLS.fetch_add(1, Ordering::Relaxed);
+        allocator.set_out_of_memory_handler(None);
+        false
+    }
+
+    alloc.set_out_of_memory_handler(Some(one_shot_handler));
+    let huge = Size::from_bytes_usize(8192).unwrap();
+    assert!(alloc.alloc(one, huge).is_none());
+    assert!(alloc.alloc(one, huge).is_none());
+    assert_eq!(ONE_SHOT_CALLS.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_fit_strategy() {
+    extern crate alloc;
+
+    let one = Size::from_bytes_usize(32).unwrap();
+    let granules = |count: usize| Size::from_bytes_usize(count * 32).unwrap();
+
+    let mut buffer = alloc::vec![0_u8; 4096 * 32 + 32];
+    let mut alloc = unsafe { Allocator::from_region(buffer.as_mut_ptr(), buffer.len()) };
+
+    // Two free chunks in the same bin, with the smaller one first in the free list.
+    let small = alloc.alloc(one, granules(1023)).unwrap();
+    let _a = alloc.alloc(one, one).unwrap();
+    let big = alloc.alloc(one, granules(1029)).unwrap();
+    let _b = alloc.alloc(one, one).unwrap();
+    let remaining = alloc.free_bins().map(|bin| bin.free_space as usize).sum::<usize>();
+    let _c = alloc.alloc(one, granules(remaining / 32 - 1)).unwrap();
+    unsafe {
+        alloc.free(big);
+        alloc.free(small);
+    }
+
+    assert_eq!(alloc.free_bins().map(|bin| bin.free_chunks).collect::<alloc::vec::Vec<_>>(), [2]);
+    assert_eq!(alloc.fit_strategy(), FitStrategy::FirstInBin);
+    assert_eq!(alloc.try_alloc(one, granules(1025)), Err(AllocError::OutOfSpace));
+
+    alloc.set_fit_strategy(FitStrategy::BoundedSearch(1));
+    assert_eq!(alloc.try_alloc(one, granules(1025)), Err(AllocError::OutOfSpace));
+
+    alloc.set_fit_strategy(FitStrategy::BoundedSearch(2));
+    let pointer = alloc.try_alloc(one, granules(1025)).unwrap();
+    assert_eq!(pointer, big);
+    unsafe { alloc.free(pointer) };
+
+    // Now the bigger chunk is first in the free list.
+    alloc.set_fit_strategy(FitStrategy::FirstInBin);
+    let pointer = alloc.alloc(one, granules(1000)).unwrap();
+    assert_eq!(pointer, big);
+    unsafe { alloc.free(pointer) };
+
+    alloc.set_fit_strategy(FitStrategy::BestFit);
+    let pointer = alloc.alloc(one, granules(1000)).unwrap();
+    assert_eq!(pointer, small);
+    unsafe { alloc.free(pointer) };
+
+    let pointer = alloc.alloc(one, granules(1025)).unwrap();
+    assert_eq!(pointer, big);
+    unsafe { alloc.free(pointer) };
+}
+
+#[test]
+fn test_fit_strategy_lowest_address() {
+    let one = Size::from_bytes_usize(32).unwrap();
+    let granules = |count: usize| Size::from_bytes_usize(count * 32).unwrap();
+
+    let mut buffer = Array([0_u8; 2048 * 32]);
+    let mut alloc = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+
+    // A big free chunk at the start of the heap, and a smaller one at its end which wasn't touched yet.
+    let a = alloc.alloc(one, granules(999)).unwrap();
+    let b = alloc.alloc(one, one).unwrap();
+    let _c = alloc.alloc(one, granules(800)).unwrap();
+    unsafe { alloc.free(a) };
+
+    let allocated_space = alloc.allocated_space();
+    let pointer = alloc.alloc(one, granules(10)).unwrap();
+    assert!(pointer > a);
+    assert!(alloc.allocated_space() > allocated_space);
+    unsafe { alloc.free(pointer) };
+
+    alloc.set_fit_strategy(FitStrategy::LowestAddress);
+    let allocated_space = alloc.allocated_space();
+    let pointer = alloc.alloc(one, granules(10)).unwrap();
+    assert_eq!(pointer, a);
+    assert_eq!(alloc.allocated_space(), allocated_space);
+
+    let pointer = alloc.alloc(one, granules(10)).unwrap();
+    assert!(pointer < b);
+    assert_eq!(alloc.allocated_space(), allocated_space);
+}
+
+#[test]
+fn test_batch() {
+    use core::mem::MaybeUninit;
+    use core::ptr::NonNull;
+
+    let one = Size::from_bytes_usize(32).unwrap();
+    let two = Size::from_bytes_usize(64).unwrap();
+
+    let mut buffer = Array([0_u8; 4096]);
+    let mut alloc = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+
+    // The objects are carved out one after another.
+    let mut out = [MaybeUninit::<NonNull<u8>>::uninit(); 16];
+    assert_eq!(alloc.alloc_batch(one, one, &mut out[..10]), 10);
+    let pointers: [NonNull<u8>; 10] = core::array::from_fn(|index| unsafe { out[index].assume_init() });
+    for pair in pointers.windows(2) {
+        assert_eq!(pair[1].as_ptr().addr() - pair[0].as_ptr().addr(), 64);
+    }
+    assert_eq!(alloc.used_space(), Size::from_bytes_usize(320).unwrap());
+
+    unsafe { alloc.free_batch(&pointers) };
+    assert_eq!(alloc.used_space(), Size::from_bytes_usize(0).unwrap());
+    assert_eq!(alloc.free_bins().count(), 1);
+
+    // Only as many objects as fit are allocated.
+    let mut out = [MaybeUninit::<NonNull<u8>>::uninit(); 64];
+    assert_eq!(alloc.alloc_batch(one, one, &mut out), 64);
+    assert_eq!(alloc.alloc_batch(one, one, &mut out), 0);
+    unsafe { alloc.reset() };
+
+    // The budget is respected, with the header of every object counted against it.
+    alloc.set_budget(Some(Size::from_bytes_usize(64 * 5).unwrap()));
+    assert_eq!(alloc.alloc_batch(one, one, &mut out), 5);
+    unsafe { alloc.reset() };
+    alloc.set_budget(None);
+
+    // Bigger alignments fall back to allocating the objects one by one.
+    assert_eq!(alloc.alloc_batch(two, one, &mut out[..8]), 8);
+    for pointer in &out[..8] {
+        assert_eq!(unsafe { pointer.assume_init() }.as_ptr().addr() % 64, 0);
+    }
+    unsafe { alloc.reset() };
+
+    let alloc = Mutex::new(alloc);
+    let layout = core::alloc::Layout::from_size_align(24, 8).unwrap();
+    assert_eq!(alloc.alloc_batch(layout, &mut out[..4]), 4);
+    let pointers: [NonNull<u8>; 4] = core::array::from_fn(|index| unsafe { out[index].assume_init() });
+    unsafe { alloc.free_batch(&pointers) };
+    assert_eq!(alloc.lock().used_space(), Size::from_bytes_usize(0).unwrap());
+}
+
+#[test]
+fn test_large_alignment() {
+    extern crate alloc;
+
+    const KB: usize = 1024;
+    const MB: usize = 1024 * 1024;
+
+    let one = Size::from_bytes_usize(32).unwrap();
+
+    let mut buffer = alloc::vec![0_u8; 6 * MB];
+    let region = unsafe { buffer.as_mut_ptr().add(buffer.as_ptr().align_offset(2 * MB)) };
+    let mut alloc = unsafe { Allocator::from_region(region, 4 * MB) };
+
+    let small = alloc.alloc(one, one).unwrap();
+
+    // This takes the whole upper half of the heap, so it wouldn't fit if the worst case alignment padding was requested.
+    let huge = alloc
+        .alloc(Size::from_bytes_usize(2 * MB).unwrap(), Size::from_bytes_usize(2 * MB).unwrap())
+        .unwrap();
+    assert_eq!(huge.as_ptr(), unsafe { region.add(2 * MB) });
+
+    // The space before it is still usable.
+    for align in [4 * KB, 64 * KB, 4 * KB] {
+        let size = Size::from_bytes_usize(align).unwrap();
+        let pointer = alloc.alloc(size, size).unwrap();
+        assert_eq!(pointer.as_ptr().addr() % align, 0);
+        assert!(pointer > small && pointer < huge);
+    }
+
+    let pointer = alloc.alloc(one, Size::from_bytes_usize(MB).unwrap()).unwrap();
+    assert!(pointer < huge);
+
+    assert!(alloc.alloc(Size::from_bytes_usize(2 * MB).unwrap(), one).is_none());
+    unsafe { alloc.free(huge) };
+    assert!(alloc.alloc(Size::from_bytes_usize(2 * MB).unwrap(), one).is_some());
+}
+
+#[test]
+fn test_allocation_info() {
+    let one = Size::from_bytes_usize(32).unwrap();
+    let two = Size::from_bytes_usize(64).unwrap();
+    let zero = Size::from_bytes_usize(0).unwrap();
+
+    let mut buffer_1 = Array([0_u8; 1024]);
+    let mut buffer_2 = Array([0_u8; 1024]);
+    let mut alloc_1 = Allocator::new(unsafe { ArrayPointer::new(&mut buffer_1) });
+    let mut alloc_2 = Allocator::new(unsafe { ArrayPointer::new(&mut buffer_2) });
+
+    let a = alloc_1.alloc(one, two).unwrap();
+    let b = alloc_1.alloc(one, zero).unwrap();
+    let c = alloc_1.alloc(one, one).unwrap();
+    let d = alloc_2.alloc(one, one).unwrap();
+
+    assert!(alloc_1.owns(a.as_ptr()) && alloc_1.owns(c.as_ptr()));
+    assert!(!alloc_1.owns(d.as_ptr()));
+    assert!(alloc_2.owns(d.as_ptr()) && !alloc_2.owns(a.as_ptr()));
+    assert!(!alloc_1.owns(core::ptr::null()));
+    assert_eq!(alloc_2.allocation_info(a.as_ptr()), None);
+
+    assert_eq!(
+        alloc_1.allocation_info(a.as_ptr()),
+        Some(AllocInfo {
+            start: a,
+            usable_size: 64,
+            is_allocated: true,
+            offset: 0
+        })
+    );
+    assert_eq!(
+        alloc_1.allocation_info(unsafe { a.as_ptr().add(40) }),
+        Some(AllocInfo {
+            start: a,
+            usable_size: 64,
+            is_allocated: true,
+            offset: 40
+        })
+    );
+    assert_eq!(
+        alloc_1.allocation_info(b.as_ptr()),
+        Some(AllocInfo {
+            start: b,
+            usable_size: 0,
+            is_allocated: true,
+            offset: 0
+        })
+    );
+
+    // The header of a chunk.
+    assert_eq!(alloc_1.allocation_info(unsafe { c.as_ptr().sub(1) }), None);
+
+    unsafe { alloc_1.free(a) };
+    let info = alloc_1.allocation_info(unsafe { a.as_ptr().add(8) }).unwrap();
+    assert_eq!(info.start, a);
+    assert!(!info.is_allocated);
+    assert_eq!(info.offset, 8);
+
+    // The space at the end of the heap is free too.
+    let info = alloc_1.allocation_info(unsafe { c.as_ptr().add(64) }).unwrap();
+    assert!(!info.is_allocated);
+    assert_eq!(info.offset, 0);
+    assert_eq!(info.usable_size, 1024 - 32 * 7);
+}
+
+#[test]
+fn test_sized() {
+    let one = Size::from_bytes_usize(32).unwrap();
+
+    let mut buffer = Array([0_u8; 1024]);
+    let mut alloc = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+
+    let a = alloc.alloc_sized(one, Size::from_bytes_usize(20).unwrap()).unwrap();
+    assert_eq!(a.len(), 32);
+
+    let b = unsafe { alloc.realloc_sized(a.cast(), one, Size::from_bytes_usize(100).unwrap()) }.unwrap();
+    assert_eq!(b.len(), 128);
+    assert_eq!(unsafe { Allocator::<ArrayPointer<1024>>::usable_size(b.cast()) }, 128);
+
+    let c = unsafe { alloc.realloc_sized(b.cast(), one, Size::from_bytes_usize(33).unwrap()) }.unwrap();
+    assert_eq!(c.len(), 64);
+
+    assert!(alloc.alloc_sized(one, Size::from_bytes_usize(4096).unwrap()).is_none());
+    assert!(unsafe { alloc.realloc_sized(c.cast(), one, Size::from_bytes_usize(0).unwrap()) }.is_none());
+    assert_eq!(alloc.used_space(), Size::from_bytes_usize(0).unwrap());
+
+    // A free chunk which would be left with no room for data is absorbed, and that's reflected in the returned length.
+    let a = alloc.alloc_sized(one, Size::from_bytes_usize(960).unwrap()).unwrap();
+    assert_eq!(a.len(), 992);
+    assert_eq!(alloc.used_space(), Size::from_bytes_usize(992).unwrap());
+
+    let b = unsafe { alloc.realloc_sized(a.cast(), one, Size::from_bytes_usize(992).unwrap()) }.unwrap();
+    assert_eq!(b.len(), 992);
+    unsafe { alloc.free(b.cast()) };
+    assert_eq!(alloc.used_space(), Size::from_bytes_usize(0).unwrap());
+}
+
+#[test]
+fn test_budget() {
+    let one = Size::from_bytes_usize(1).unwrap();
+    let size = Size::from_bytes_usize(64).unwrap();
+
+    let mut buffer = Array([0_u8; 4096]);
+    let mut alloc = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+    assert_eq!(alloc.remaining_budget(), None);
+
+    // Every allocation is charged for its header too.
+    alloc.set_budget(Some(Size::from_bytes_usize(192).unwrap()));
+    let a = alloc.alloc(one, size).unwrap();
+    let b = alloc.alloc(one, size).unwrap();
+    assert_eq!(alloc.charged_space(), Size::from_bytes_usize(192).unwrap());
+    assert_eq!(alloc.remaining_budget(), Some(Size::from_bytes_usize(0).unwrap()));
+    assert_eq!(alloc.try_alloc(one, one), Err(AllocError::BudgetExceeded));
+    assert!(AllocError::BudgetExceeded.is_out_of_memory());
+
+    unsafe {
+        alloc.free(b);
+        assert_eq!(alloc.remaining_budget(), Some(Size::from_bytes_usize(96).unwrap()));
+        assert_eq!(
+            alloc.try_grow_inplace(a, Size::from_bytes_usize(192).unwrap()),
+            Err(AllocError::BudgetExceeded)
+        );
+        assert_eq!(
+            alloc.try_grow_inplace(a, Size::from_bytes_usize(160).unwrap()),
+            Ok(Size::from_bytes_usize(160).unwrap())
+        );
+        alloc.free(a);
+    }
+
+    // Zero-sized allocations still take up a header.
+    let zero = Size::from_bytes_usize(0).unwrap();
+    alloc.set_budget(Some(Size::from_bytes_usize(64).unwrap()));
+    let a = alloc.alloc(one, zero).unwrap();
+    let b = alloc.alloc(one, zero).unwrap();
+    assert_eq!(alloc.try_alloc(one, zero), Err(AllocError::BudgetExceeded));
+    unsafe {
+        alloc.free(a);
+        alloc.free(b);
+    }
+
+    // A realloc which has to move the allocation only needs the budget for the difference.
+    alloc.set_budget(None);
+    let a = alloc.alloc(one, size).unwrap();
+    let b = alloc.alloc(one, size).unwrap();
+    alloc.set_budget(Some(Size::from_bytes_usize(256).unwrap()));
+    unsafe {
+        assert!(alloc.try_realloc(a, one, Size::from_bytes_usize(96).unwrap()).is_ok());
+        assert_eq!(alloc.remaining_budget(), Some(Size::from_bytes_usize(32).unwrap()));
+        assert_eq!(
+            alloc.try_realloc(b, one, Size::from_bytes_usize(160).unwrap()),
+            Err(AllocError::BudgetExceeded)
+        );
+    }
+
+    alloc.set_budget(Some(Size::from_bytes_usize(32).unwrap()));
+    assert_eq!(alloc.remaining_budget(), Some(Size::from_bytes_usize(0).unwrap()));
+    alloc.set_budget(None);
+    assert!(alloc.alloc(one, size).is_some());
+}
+
+#[cfg(picoalloc_linux_syscalls)]
+#[test]
+fn test_linux_env_options() {
+    let env = LinuxEnv::<{ 32 * 1024 * 1024 }>::new()
+        .with_huge_pages(true)
+        .with_no_reserve(true)
+        .with_prefault(100 * 1024);
+
+    let mut allocator = Allocator::new(env);
+    let a = allocator
+        .alloc(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(100).unwrap())
+        .unwrap();
+    assert_eq!(allocator.env().committed_space(), 128 * 1024);
+    assert_eq!((a.as_ptr() as usize - 32) % (2 * 1024 * 1024), 0);
+    unsafe { allocator.free(a) };
+
+    test_many_small_allocations(LinuxEnv::<{ 1024 * 1024 }>::new().with_prefault(usize::MAX / 2), 16384);
+
+    let mut allocator = Allocator::new(LinuxEnv::<{ 1024 * 1024 }>::new().with_prefault(usize::MAX));
+    let a = allocator
+        .alloc(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(100).unwrap())
+        .unwrap();
+    assert_eq!(allocator.env().committed_space(), 1024 * 1024);
+    unsafe { allocator.free(a) };
+}
+
+#[test]
+fn test_slice_env() {
+    extern crate alloc;
+
+    // Deliberately misalign the region; whatever the alignment of the buffer at least 4096 bytes will be usable.
+    let mut buffer = alloc::vec![0xff_u8; 4096 + 64];
+    let pointer = unsafe { buffer.as_mut_ptr().add(1) };
+    test_allocator(unsafe { SliceEnv::new(pointer, 4096 + 63) });
+
+    let mut alloc = unsafe { Allocator::from_region(pointer, 4096 + 63) };
+    let size = Size::from_bytes_usize(64).unwrap();
+    let a = alloc.alloc_zeroed(Size::from_bytes_usize(1).unwrap(), size).unwrap();
+    assert_eq!(a.as_ptr() as usize % 32, 0);
+    assert!(unsafe { core::slice::from_raw_parts(a.as_ptr(), 64) }.iter().all(|&byte| byte == 0));
+    assert!(alloc
+        .alloc(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(4096).unwrap())
+        .is_none());
+    unsafe { alloc.free(a) };
+
+    let mut alloc = unsafe { Allocator::from_region(pointer, 16) };
+    assert_eq!(
+        alloc.try_alloc(Size::from_bytes_usize(1).unwrap(), size),
+        Err(AllocError::InitializationFailed)
+    );
+}
+
+#[test]
+fn test_snapshot() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    fn region(buffer: &mut [u8], length: usize) -> SliceEnv {
+        unsafe { SliceEnv::new(buffer.as_mut_ptr().add(buffer.as_ptr().align_offset(32)), length) }
+    }
+
+    fn offsets<E: Env>(alloc: &mut Allocator<E>) -> Vec<usize> {
+        (1..16)
+            .map(|nth| {
+                let pointer = alloc.alloc(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(nth * 48).unwrap());
+                pointer.unwrap().as_ptr() as usize - alloc.base_address() as usize
+            })
+            .collect()
+    }
+
+    let one = Size::from_bytes_usize(1).unwrap();
+    let mut old_buffer = alloc::vec![0_u8; 16384 + 32];
+    let mut alloc = Allocator::new(region(&mut old_buffer, 16384));
+
+    // A snapshot of an allocator which wasn't used yet.
+    let mut snapshot = alloc::vec![0; alloc.snapshot_size()];
+    assert_eq!(alloc.snapshot(&mut snapshot), Ok(snapshot.len()));
+    let mut new_buffer = alloc::vec![0_u8; 16384 + 32];
+    let restored = unsafe { Allocator::restore(region(&mut new_buffer, 16384), &snapshot) }.unwrap();
+    assert!(restored.base_address().is_null());
+    drop(restored);
+
+    let mut pointers = Vec::new();
+    for nth in 0..16_u8 {
+        let pointer = alloc.alloc(one, Size::from_bytes_usize(32 + nth as usize * 40).unwrap()).unwrap();
+        unsafe { pointer.as_ptr().write_bytes(nth, 32) };
+        pointers.push(pointer);
+    }
+
+    for nth in [1, 2, 5, 9, 10, 11, 15] {
+        unsafe { alloc.free(pointers[nth]) };
+    }
+
+    let mut snapshot = alloc::vec![0; alloc.snapshot_size() + 16];
+    assert_eq!(alloc.snapshot(&mut snapshot[..16]), Err(SnapshotError::BufferTooSmall));
+    let length = alloc.snapshot(&mut snapshot).unwrap();
+    assert_eq!(length, alloc.snapshot_size());
+    let heap_offset = length - alloc.allocated_space().bytes() as usize;
+
+    let mut restored = unsafe { Allocator::restore(region(&mut new_buffer, 16384), &snapshot) }.unwrap();
+    assert_eq!(restored.used_space(), alloc.used_space());
+    assert_eq!(restored.allocated_space(), alloc.allocated_space());
+    assert_eq!(restored.fragmentation(), alloc.fragmentation());
+    assert!(restored.free_bins().eq(alloc.free_bins()));
+
+    for nth in [0, 3, 4, 6, 7, 8, 12, 13, 14] {
+        let offset = pointers[nth].as_ptr() as usize - alloc.base_address() as usize;
+        let pointer = restored.base_address().wrapping_add(offset);
+        assert!(unsafe { core::slice::from_raw_parts(pointer, 32) }
+            .iter()
+            .all(|&byte| byte == nth as u8));
+    }
+
+    // The restored allocator behaves exactly like the original one.
+    let expected_offsets = offsets(&mut alloc);
+    drop(alloc);
+    old_buffer.fill(0xff);
+    assert_eq!(offsets(&mut restored), expected_offsets);
+    drop(restored);
+
+    let restore = |buffer: &mut [u8], length: usize, snapshot: &[u8]| unsafe { Allocator::restore(region(buffer, length), snapshot) }.err();
+    assert_eq!(restore(&mut new_buffer, 8192, &snapshot), Some(SnapshotError::TotalSpaceMismatch));
+    assert_eq!(
+        restore(&mut new_buffer, 16384, &snapshot[..length - 1]),
+        Some(SnapshotError::Truncated)
+    );
+
+    let mut corrupted = snapshot.clone();
+    corrupted[0] ^= 1;
+    assert_eq!(restore(&mut new_buffer, 16384, &corrupted), Some(SnapshotError::InvalidMagic));
+
+    let mut corrupted = snapshot.clone();
+    corrupted[8] += 1;
+    assert_eq!(restore(&mut new_buffer, 16384, &corrupted), Some(SnapshotError::UnsupportedVersion));
+
+    // The size of the first chunk.
+    let mut corrupted = snapshot.clone();
+    corrupted[heap_offset + 4] ^= 2;
+    assert_eq!(restore(&mut new_buffer, 16384, &corrupted), Some(SnapshotError::Corrupted));
+}
+
+#[cfg(feature = "relocatable")]
+#[test]
+fn test_relocate() {
+    extern crate alloc;
+
+    let one = Size::from_bytes_usize(1).unwrap();
+    let mut old_buffer = alloc::vec![0_u8; 8192 + 32];
+    let mut new_buffer = alloc::vec![0_u8; 8192 + 32];
+    let mut alloc = unsafe { Allocator::from_region(old_buffer.as_mut_ptr(), old_buffer.len()) };
+
+    let mut pointers = alloc::vec::Vec::new();
+    for nth in 0..8_u8 {
+        let pointer = alloc.alloc(one, Size::from_bytes_usize(64 + nth as usize * 32).unwrap()).unwrap();
+        unsafe { pointer.as_ptr().write_bytes(nth, 64) };
+        pointers.push(pointer);
+    }
+
+    // Leave some holes in the free lists.
+    for nth in (0..8).step_by(2) {
+        unsafe { alloc.free(pointers[nth]) };
+    }
+
+    let old_base = alloc.base_address();
+    let new_base = unsafe { new_buffer.as_mut_ptr().add(new_buffer.as_ptr().align_offset(32)) };
+    unsafe {
+        core::ptr::copy_nonoverlapping(old_base, new_base, alloc.allocated_space().bytes() as usize);
+        alloc.relocate(new_base);
+    }
+
+    // Trash the old region to make sure it's not used anymore.
+    old_buffer.fill(0xff);
+    assert_eq!(alloc.base_address(), new_base);
+
+    for nth in (1..8).step_by(2) {
+        let offset = unsafe { pointers[nth].as_ptr().offset_from(old_base) } as usize;
+        let pointer = core::ptr::NonNull::new(new_base.wrapping_add(offset)).unwrap();
+        assert!(unsafe { core::slice::from_raw_parts(pointer.as_ptr(), 64) }
+            .iter()
+            .all(|&byte| byte == nth as u8));
+        unsafe { alloc.free(pointer) };
+    }
+
+    let new_buffer_range = new_buffer.as_ptr_range();
+    for nth in 0..8 {
+        let pointer = alloc.alloc(one, Size::from_bytes_usize(64 + nth * 32).unwrap()).unwrap();
+        assert!(new_buffer_range.contains(&pointer.as_ptr().cast_const()));
+    }
+
+    assert!(old_buffer.iter().all(|&byte| byte == 0xff));
+}
+
+#[cfg(all(picoalloc_linux_syscalls, feature = "shared_heap"))]
+#[test]
+fn test_shared_heap() {
+    let one = Size::from_bytes_usize(1).unwrap();
+    let size = Size::from_bytes_usize(4096).unwrap();
+
+    let first = SharedHeap::<{ 1024 * 1024 }>::create().unwrap();
+    let second = unsafe { SharedHeap::<{ 1024 * 1024 }>::open(first.fd()) }.unwrap();
+    assert!(unsafe { SharedHeap::<{ 2 * 1024 * 1024 }>::open(first.fd()) }.is_none());
+
+    // Opening the heap doesn't move the offset of the original file descriptor.
+    {
+        use std::io::Seek;
+        use std::os::fd::FromRawFd;
+
+        let mut file = core::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(first.fd()) });
+        assert_eq!(file.stream_position().unwrap(), 0);
+    }
+    assert_ne!(first.pointer_at(0), second.pointer_at(0));
+
+    let a = first.lock().alloc(one, size).unwrap();
+    unsafe { a.as_ptr().write_bytes(0xaa, 4096) };
+
+    let offset = first.offset_of(a);
+    let a_in_second = core::ptr::NonNull::new(second.pointer_at(offset)).unwrap();
+    assert!(unsafe { core::slice::from_raw_parts(a_in_second.as_ptr(), 4096) }
+        .iter()
+        .all(|&byte| byte == 0xaa));
+
+    // Allocations and frees done through either mapping are visible in both.
+    let b = second.lock().alloc(one, size).unwrap();
+    assert_eq!(first.lock().used_space(), Size::from_bytes_usize(8192).unwrap());
+    unsafe {
+        second.lock().free(a_in_second);
+        first
+            .lock()
+            .free(core::ptr::NonNull::new(first.pointer_at(second.offset_of(b))).unwrap());
+    }
+
+    drop(first);
+    let mut allocator = second.lock();
+    assert_eq!(allocator.used_space(), Size::from_bytes_usize(0).unwrap());
+    assert_eq!(allocator.fragmentation().free_chunks, 1);
+    assert!(allocator.alloc(one, size).is_some());
+}
+
+#[cfg(all(picoalloc_linux_syscalls, feature = "persistent_heap"))]
+#[test]
+fn test_persistent_heap() {
+    use core::ptr::NonNull;
+
+    type Heap = PersistentHeap<{ 1024 * 1024 }>;
+
+    let one = Size::from_bytes_usize(1).unwrap();
+    let path = std::env::temp_dir().join(std::format!("picoalloc-persistent-heap-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+
+    let heap = Heap::open(&c_path).unwrap();
+    assert!(Heap::open(&c_path).is_none());
+    assert!(heap.root().is_none());
+
+    let (a, b, c) = {
+        let mut allocator = heap.lock();
+        let a = allocator.alloc(one, Size::from_bytes_usize(64).unwrap()).unwrap();
+        let b = allocator.alloc(one, Size::from_bytes_usize(128).unwrap()).unwrap();
+        let c = allocator.alloc(one, Size::from_bytes_usize(256).unwrap()).unwrap();
+        unsafe { allocator.free(b) };
+        (a, b, c)
+    };
+
+    unsafe {
+        a.as_ptr().cast::<usize>().write(heap.offset_of(c));
+        c.as_ptr().write_bytes(0xcc, 256);
+    }
+
+    heap.set_root(Some(a));
+    heap.lock().set_fit_strategy(FitStrategy::BoundedSearch(4));
+    heap.lock().set_budget(Some(Size::from_bytes_usize(512 * 1024).unwrap()));
+    assert!(heap.flush());
+    let used_space = heap.lock().used_space();
+    let b_offset = heap.offset_of(b);
+    drop(heap);
+
+    assert!(PersistentHeap::<{ 2 * 1024 * 1024 }>::open(&c_path).is_none());
+
+    let heap = Heap::open(&c_path).unwrap();
+    let root = heap.root().unwrap();
+    let c = heap.pointer_at(unsafe { root.as_ptr().cast::<usize>().read() });
+    assert!(unsafe { core::slice::from_raw_parts(c, 256) }.iter().all(|&byte| byte == 0xcc));
+    assert_eq!(heap.lock().used_space(), used_space);
+    assert_eq!(heap.lock().fit_strategy(), FitStrategy::BoundedSearch(4));
+    assert_eq!(heap.lock().budget(), Some(Size::from_bytes_usize(512 * 1024).unwrap()));
+
+    // Simulate a process which has died in the middle of an allocator operation.
+    let d = {
+        let mut allocator = heap.lock();
+        let d = allocator.alloc(one, Size::from_bytes_usize(4096).unwrap()).unwrap();
+        unsafe { allocator.free(NonNull::new(c).unwrap()) };
+        core::mem::forget(allocator);
+        heap.offset_of(d)
+    };
+    drop(heap);
+
+    let heap = Heap::open(&c_path).unwrap();
+    let mut allocator = heap.lock();
+    assert_eq!(allocator.used_space(), Size::from_bytes_usize(64 + 4096).unwrap());
+
+    // The chunks of `b` and `c` were merged.
+    let fragmentation = allocator.fragmentation();
+    assert_eq!(fragmentation.allocated_chunks, 2);
+    assert_eq!(fragmentation.free_chunks, 2);
+    assert_eq!(
+        allocator
+            .alloc(one, Size::from_bytes_usize(128 + 256).unwrap())
+            .map(|pointer| heap.offset_of(pointer)),
+        Some(b_offset)
+    );
+
+    unsafe {
+        allocator.free(NonNull::new(heap.pointer_at(d)).unwrap());
+    }
+
+    drop(allocator);
+    drop(heap);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "persistent_heap")]
+#[test]
+fn test_recover_interrupted() {
+    use crate::allocator::interrupt;
+    use core::ptr::NonNull;
+
+    type TestAllocator = Allocator<ArrayPointer<8192>>;
+    type Operation = fn(&mut TestAllocator, [NonNull<u8>; 3]);
+
+    let one = Size::from_bytes_usize(1).unwrap();
+    let size = |bytes| Size::from_bytes_usize(bytes).unwrap();
+
+    // Leaves the heap as `[a][free][c][d][free]`.
+    let setup = |allocator: &mut TestAllocator| {
+        let a = allocator.alloc(one, size(64)).unwrap();
+        let b = allocator.alloc(one, size(128)).unwrap();
+        let c = allocator.alloc(one, size(256)).unwrap();
+        let d = allocator.alloc(one, size(64)).unwrap();
+        unsafe { allocator.free(b) };
+        [a, c, d]
+    };
+
+    let operations: [Operation; 8] = [
+        |allocator, _| {
+            allocator.alloc(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(64).unwrap());
+        },
+        |allocator, _| {
+            allocator.alloc(Size::from_bytes_usize(256).unwrap(), Size::from_bytes_usize(64).unwrap());
+        },
+        |allocator, _| {
+            let mut out = [core::mem::MaybeUninit::uninit(); 4];
+            allocator.alloc_batch(Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(32).unwrap(), &mut out);
+        },
+        |allocator, [_, c, _]| unsafe { allocator.free(c) },
+        |allocator, [_, _, d]| unsafe { allocator.free(d) },
+        |allocator, [_, c, _]| unsafe { allocator.shrink_inplace(c, Size::from_bytes_usize(32).unwrap()) },
+        |allocator, [a, _, _]| unsafe {
+            allocator.grow_inplace(a, Size::from_bytes_usize(128).unwrap());
+        },
+        |allocator, [_, c, _]| unsafe {
+            allocator.realloc(c, Size::from_bytes_usize(1).unwrap(), Size::from_bytes_usize(320).unwrap());
+        },
+    ];
+
+    for operation in operations {
+        let mut interrupted_count = 0;
+        for stores in 0.. {
+            let mut buffer = Array([0_u8; 8192]);
+            let mut allocator = core::mem::ManuallyDrop::new(Allocator::new(unsafe { ArrayPointer::new(&mut buffer) }));
+            let pointers = setup(&mut allocator);
+
+            interrupt::after_stores(Some(stores));
+            let result = std::panic::catch_unwind(core::panic::AssertUnwindSafe(|| operation(&mut allocator, pointers)));
+            interrupt::after_stores(None);
+            if result.is_ok() {
+                break;
+            }
+
+            interrupted_count += 1;
+            let base_address = allocator.base_address();
+            let mut allocator = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+            assert!(unsafe { allocator.recover(base_address, allocator.env().total_space()) });
+
+            // The allocation which wasn't touched must still be there.
+            assert!(allocator.allocation_info(pointers[0].as_ptr()).unwrap().is_allocated);
+
+            let mut new_pointers = [pointers[0]; 8];
+            for pointer in &mut new_pointers {
+                *pointer = allocator.alloc(one, size(96)).unwrap();
+            }
+
+            for pointer in new_pointers {
+                unsafe { allocator.free(pointer) };
+            }
+        }
+
+        assert!(interrupted_count > 0);
+    }
+
+    // A `prev_chunk_size` which doesn't match the chunks before it is detected.
+    let mut buffer = Array([0_u8; 8192]);
+    let mut allocator = core::mem::ManuallyDrop::new(Allocator::new(unsafe { ArrayPointer::new(&mut buffer) }));
+    let [_, _, d] = setup(&mut allocator);
+    let base_address = allocator.base_address();
+    unsafe { d.as_ptr().sub(32).cast::<u32>().write(5) };
+
+    let mut allocator = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+    assert!(!unsafe { allocator.recover(base_address, allocator.env().total_space()) });
+}
+
+#[test]
+fn test_reset() {
+    let one = Size::from_bytes_usize(1).unwrap();
+    let size = Size::from_bytes_usize(96).unwrap();
+    let mut buffer = Array([0_u8; 16384]);
+    let mut allocator = Allocator::new(unsafe { ArrayPointer::new(&mut buffer) });
+    unsafe { allocator.reset() };
+    assert!(allocator.base_address().is_null());
+
+    let first = allocator.alloc(one, size).unwrap();
+    let mut pointers = [first; 64];
+    for pointer in &mut pointers[1..] {
+        *pointer = allocator.alloc(one, size).unwrap();
+    }
+
+    for pointer in pointers.iter().step_by(3) {
+        unsafe { allocator.free(*pointer) };
+    }
+
+    let allocated_space = allocator.allocated_space();
+    unsafe { allocator.reset() };
+    assert_eq!(allocator.used_space(), Size::from_bytes_usize(0).unwrap());
+    assert_eq!(allocator.allocated_space(), allocated_space);
+    assert_eq!(allocator.free_bins().count(), 1);
+
+    let fragmentation = allocator.fragmentation();
+    assert_eq!(fragmentation.allocated_chunks, 0);
+    assert_eq!(fragmentation.free_chunks, 1);
+    assert_eq!(fragmentation.free_space, 16384);
+
+    assert_eq!(allocator.alloc(one, size), Some(first));
+    assert!(allocator.alloc(one, Size::from_bytes_usize(8192).unwrap()).is_some());
+
+    // Decommitting is not supported by this environment, so this is just a reset.
+    let allocated_space = allocator.allocated_space();
+    unsafe { allocator.reset_and_decommit(Size::from_bytes_usize(0).unwrap()) };
+    assert_eq!(allocator.allocated_space(), allocated_space);
+    assert_eq!(allocator.alloc(one, size), Some(first));
+}
+
+#[cfg(feature = "lock_api")]
+#[test]
+fn test_lock_api() {
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    struct RawSpinLock(AtomicBool);
+
+    unsafe impl lock_api::RawMutex for RawSpinLock {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = RawSpinLock(AtomicBool::new(false));
+        type GuardMarker = lock_api::GuardSend;
+
+        fn lock(&self) {
+            while !self.try_lock() {}
+        }
+
+        fn try_lock(&self) -> bool {
+            self.0.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        }
+
+        unsafe fn unlock(&self) {
+            self.0.store(false, Ordering::Release);
+        }
+    }
+
+    let mut buffer = Array([0_u8; 1024]);
+    let alloc = LockApiAllocator::<RawSpinLock, _>::new(Allocator::new(unsafe { ArrayPointer::new(&mut buffer) }));
+
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    unsafe {
+        let a = alloc.alloc(layout);
+        assert!(!a.is_null());
+        let b = alloc.alloc_zeroed(layout);
+        assert_eq!(core::slice::from_raw_parts(b, 40), [0; 40]);
+        let a = alloc.realloc(a, layout, 100);
+        assert!(!a.is_null());
+        assert_eq!(alloc.lock().used_space(), Size::from_bytes_usize(128 + 64).unwrap());
+        assert!(alloc.alloc(Layout::from_size_align(4096, 8).unwrap()).is_null());
+
+        alloc.dealloc(a, layout);
+        alloc.dealloc(b, layout);
+    }
+
+    let alloc = alloc.into_inner();
+    assert_eq!(alloc.lock().used_space(), Size::from_bytes_usize(0).unwrap());
+}
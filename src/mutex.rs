@@ -23,6 +23,11 @@ impl<T> Mutex<T> {
         }
     }
 
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
     #[inline]
     pub fn lock(&self) -> MutexGuard<T> {
         use core::sync::atomic::Ordering;
@@ -34,6 +39,20 @@ impl<T> Mutex<T> {
 
         MutexGuard(self)
     }
+
+    #[inline]
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        use core::sync::atomic::Ordering;
+        if self
+            .flag
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(MutexGuard(self))
+        } else {
+            None
+        }
+    }
 }
 
 impl<T> Drop for MutexGuard<'_, T> {
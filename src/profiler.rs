@@ -0,0 +1,739 @@
+use crate::{Env, Size, TraceEvent, TraceSink};
+
+/// The maximum number of frames captured for a single sample.
+pub const MAX_FRAMES: usize = 32;
+
+const MAX_STACKS: usize = 512;
+const MAX_LIVE_SAMPLES: usize = 1024;
+const LIVE_SAMPLE_TABLE_LENGTH: usize = MAX_LIVE_SAMPLES * 2;
+const EMPTY_HANDLE: u32 = u32::MAX;
+
+const DEFAULT_SAMPLE_INTERVAL: u64 = 512 * 1024;
+
+const MAX_MAPPINGS: usize = 64;
+const MAX_MAPPING_PATHS_LENGTH: usize = 4096;
+
+/// Captures the stack trace of the current thread.
+pub trait StackUnwinder {
+    /// Fills `frames` with return addresses, innermost first, and returns how many were written.
+    fn unwind(&mut self, frames: &mut [usize]) -> usize;
+}
+
+/// A stack unwinder which walks the chain of saved frame pointers.
+#[derive(Copy, Clone)]
+pub struct FramePointerUnwinder(());
+
+impl FramePointerUnwinder {
+    /// Creates a new frame pointer based unwinder.
+    ///
+    /// # Safety
+    ///
+    /// The whole program must be compiled with frame pointers enabled (e.g. with `-C force-frame-pointers=yes`),
+    /// otherwise the unwinder could read arbitrary memory.
+    pub const unsafe fn new() -> Self {
+        FramePointerUnwinder(())
+    }
+}
+
+impl StackUnwinder for FramePointerUnwinder {
+    #[inline(never)]
+    fn unwind(&mut self, frames: &mut [usize]) -> usize {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        {
+            const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+            let mut frame_pointer: usize;
+            unsafe {
+                #[cfg(target_arch = "x86_64")]
+                core::arch::asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack, preserves_flags));
+
+                #[cfg(target_arch = "aarch64")]
+                core::arch::asm!("mov {}, x29", out(reg) frame_pointer, options(nomem, nostack, preserves_flags));
+            }
+
+            let mut count = 0;
+            while count < frames.len() && frame_pointer != 0 && frame_pointer % core::mem::align_of::<usize>() == 0 {
+                // SAFETY: The caller of `new` guaranteed that frame pointers are available.
+                let (next_frame_pointer, return_address) = unsafe {
+                    let frame = core::ptr::with_exposed_provenance::<usize>(frame_pointer);
+                    (*frame, *frame.add(1))
+                };
+
+                if return_address == 0 {
+                    break;
+                }
+
+                frames[count] = return_address;
+                count += 1;
+
+                if next_frame_pointer <= frame_pointer || next_frame_pointer - frame_pointer > MAX_FRAME_SIZE {
+                    break;
+                }
+
+                frame_pointer = next_frame_pointer;
+            }
+
+            count
+        }
+
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        {
+            let _ = frames;
+            0
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Stack {
+    hash: u64,
+    depth: u32,
+    frames: [usize; MAX_FRAMES],
+    alloc_objects: u64,
+    alloc_space: u64,
+    inuse_objects: u64,
+    inuse_space: u64,
+}
+
+impl Stack {
+    const EMPTY: Self = Stack {
+        hash: 0,
+        depth: 0,
+        frames: [0; MAX_FRAMES],
+        alloc_objects: 0,
+        alloc_space: 0,
+        inuse_objects: 0,
+        inuse_space: 0,
+    };
+
+    fn frames(&self) -> &[usize] {
+        &self.frames[..self.depth as usize]
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Mapping {
+    start: u64,
+    limit: u64,
+    offset: u64,
+    path_start: usize,
+    path_end: usize,
+}
+
+/// The executable file mappings of the process, which are needed to symbolize the return addresses.
+struct Mappings {
+    mappings: [Mapping; MAX_MAPPINGS],
+    count: usize,
+    paths: [u8; MAX_MAPPING_PATHS_LENGTH],
+    paths_length: usize,
+}
+
+impl Mappings {
+    fn load() -> Self {
+        #[allow(unused_mut)]
+        let mut mappings = Mappings {
+            mappings: [Mapping::default(); MAX_MAPPINGS],
+            count: 0,
+            paths: [0; MAX_MAPPING_PATHS_LENGTH],
+            paths_length: 0,
+        };
+
+        #[cfg(picoalloc_linux_syscalls)]
+        crate::env::for_each_executable_mapping(|start, limit, offset, path| mappings.push(start, limit, offset, path));
+
+        mappings
+    }
+
+    #[cfg_attr(not(picoalloc_linux_syscalls), allow(dead_code))]
+    fn push(&mut self, start: u64, limit: u64, offset: u64, path: &[u8]) {
+        let path_end = self.paths_length + path.len();
+        if self.count == MAX_MAPPINGS || path_end > MAX_MAPPING_PATHS_LENGTH {
+            return;
+        }
+
+        self.paths[self.paths_length..path_end].copy_from_slice(path);
+        self.mappings[self.count] = Mapping {
+            start,
+            limit,
+            offset,
+            path_start: self.paths_length,
+            path_end,
+        };
+        self.count += 1;
+        self.paths_length = path_end;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&Mapping, &[u8])> {
+        self.mappings[..self.count]
+            .iter()
+            .map(|mapping| (mapping, &self.paths[mapping.path_start..mapping.path_end]))
+    }
+
+    /// Returns the ID of the mapping which contains `address`, if any.
+    fn find(&self, address: u64) -> Option<u64> {
+        self.mappings[..self.count]
+            .iter()
+            .position(|mapping| (mapping.start..mapping.limit).contains(&address))
+            .map(mapping_id)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct LiveSample {
+    handle: u32,
+    stack: u32,
+    objects: u64,
+    space: u64,
+}
+
+impl LiveSample {
+    const EMPTY: Self = LiveSample {
+        handle: EMPTY_HANDLE,
+        stack: 0,
+        objects: 0,
+        space: 0,
+    };
+}
+
+/// An environment which samples allocations and keeps track of where they were made.
+///
+/// On average one sample is taken for every `sample_interval` bytes allocated, with the distance
+/// between the samples being exponentially distributed, so every byte has the same chance of being sampled.
+/// The sampled values are scaled up to estimate the totals when the profile is written.
+pub struct Profiled<E, U> {
+    env: E,
+    unwinder: U,
+    is_enabled: bool,
+    sample_interval: u64,
+    bytes_until_sample: u64,
+    rng: u64,
+    dropped_samples: u64,
+    stack_count: usize,
+    stacks: [Stack; MAX_STACKS],
+    live_sample_count: usize,
+    live_samples: [LiveSample; LIVE_SAMPLE_TABLE_LENGTH],
+}
+
+impl<E, U> Profiled<E, U> {
+    pub const fn new(env: E, unwinder: U) -> Self {
+        Profiled {
+            env,
+            unwinder,
+            is_enabled: true,
+            sample_interval: DEFAULT_SAMPLE_INTERVAL,
+            bytes_until_sample: DEFAULT_SAMPLE_INTERVAL,
+            rng: 0x2545f4914f6cdd1d,
+            dropped_samples: 0,
+            stack_count: 0,
+            stacks: [Stack::EMPTY; MAX_STACKS],
+            live_sample_count: 0,
+            live_samples: [LiveSample::EMPTY; LIVE_SAMPLE_TABLE_LENGTH],
+        }
+    }
+
+    /// Sets the average number of bytes allocated between two samples.
+    pub const fn with_sample_interval(mut self, bytes: u64) -> Self {
+        if bytes == 0 {
+            self.sample_interval = 1;
+        } else {
+            self.sample_interval = bytes;
+        }
+
+        self.bytes_until_sample = self.sample_interval;
+        self
+    }
+
+    /// Sets whether new allocations are sampled; the profiler starts enabled by default.
+    pub const fn with_enabled(mut self, is_enabled: bool) -> Self {
+        self.is_enabled = is_enabled;
+        self
+    }
+
+    /// Starts or stops sampling new allocations.
+    ///
+    /// The samples which were already taken are kept.
+    pub fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_enabled = is_enabled;
+    }
+
+    /// Returns whether new allocations are sampled.
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled
+    }
+
+    /// Returns the average number of bytes allocated between two samples.
+    pub fn sample_interval(&self) -> u64 {
+        self.sample_interval
+    }
+
+    /// Returns the number of samples which were dropped because the profiler ran out of space.
+    pub fn dropped_samples(&self) -> u64 {
+        self.dropped_samples
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn next_sample_distance(&mut self) -> u64 {
+        // Draw from an exponential distribution with the mean of `sample_interval`.
+        let uniform = ((self.next_random() >> 11) + 1) as f64 / (1_u64 << 53) as f64;
+        let distance = -ln(uniform) * self.sample_interval as f64;
+        if distance < 1.0 {
+            1
+        } else {
+            distance as u64
+        }
+    }
+
+    fn live_sample_slot(&self, handle: u32) -> usize {
+        (handle as usize).wrapping_mul(0x9e3779b9) % LIVE_SAMPLE_TABLE_LENGTH
+    }
+
+    fn find_live_sample(&self, handle: u32) -> Option<usize> {
+        if self.live_sample_count == 0 {
+            return None;
+        }
+
+        let mut slot = self.live_sample_slot(handle);
+        loop {
+            let sample_handle = self.live_samples[slot].handle;
+            if sample_handle == handle {
+                return Some(slot);
+            }
+
+            if sample_handle == EMPTY_HANDLE {
+                return None;
+            }
+
+            slot = (slot + 1) % LIVE_SAMPLE_TABLE_LENGTH;
+        }
+    }
+
+    fn insert_live_sample(&mut self, sample: LiveSample) -> bool {
+        if self.live_sample_count >= MAX_LIVE_SAMPLES {
+            return false;
+        }
+
+        let mut slot = self.live_sample_slot(sample.handle);
+        while self.live_samples[slot].handle != EMPTY_HANDLE {
+            slot = (slot + 1) % LIVE_SAMPLE_TABLE_LENGTH;
+        }
+
+        self.live_samples[slot] = sample;
+        self.live_sample_count += 1;
+        true
+    }
+
+    fn remove_live_sample(&mut self, handle: u32) -> Option<LiveSample> {
+        let mut slot = self.find_live_sample(handle)?;
+        let sample = self.live_samples[slot];
+        self.live_sample_count -= 1;
+
+        // Shift back the following entries so that no holes are left in their probe sequences.
+        let mut next = slot;
+        loop {
+            self.live_samples[slot] = LiveSample::EMPTY;
+            loop {
+                next = (next + 1) % LIVE_SAMPLE_TABLE_LENGTH;
+                let next_handle = self.live_samples[next].handle;
+                if next_handle == EMPTY_HANDLE {
+                    return Some(sample);
+                }
+
+                let ideal = self.live_sample_slot(next_handle);
+                let is_between = if slot <= next {
+                    slot < ideal && ideal <= next
+                } else {
+                    slot < ideal || ideal <= next
+                };
+
+                if !is_between {
+                    break;
+                }
+            }
+
+            self.live_samples[slot] = self.live_samples[next];
+            slot = next;
+        }
+    }
+
+    fn find_or_insert_stack(&mut self, frames: &[usize]) -> Option<usize> {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &frame in frames {
+            hash = (hash ^ frame as u64).wrapping_mul(0x100000001b3);
+        }
+
+        let mut slot = (hash as usize) % MAX_STACKS;
+        for _ in 0..MAX_STACKS {
+            let stack = &self.stacks[slot];
+            if stack.depth == 0 {
+                if self.stack_count * 4 >= MAX_STACKS * 3 {
+                    return None;
+                }
+
+                let stack = &mut self.stacks[slot];
+                stack.hash = hash;
+                stack.depth = frames.len() as u32;
+                stack.frames[..frames.len()].copy_from_slice(frames);
+                self.stack_count += 1;
+                return Some(slot);
+            }
+
+            if stack.hash == hash && stack.frames() == frames {
+                return Some(slot);
+            }
+
+            slot = (slot + 1) % MAX_STACKS;
+        }
+
+        None
+    }
+
+    fn release_all(&mut self) {
+        for stack in &mut self.stacks {
+            stack.inuse_objects = 0;
+            stack.inuse_space = 0;
+        }
+
+        self.live_samples = [LiveSample::EMPTY; LIVE_SAMPLE_TABLE_LENGTH];
+        self.live_sample_count = 0;
+    }
+
+    fn release(&mut self, handle: u32) {
+        if let Some(sample) = self.remove_live_sample(handle) {
+            let stack = &mut self.stacks[sample.stack as usize];
+            stack.inuse_objects -= sample.objects;
+            stack.inuse_space -= sample.space;
+        }
+    }
+}
+
+impl<E, U: StackUnwinder> Profiled<E, U> {
+    fn on_alloc(&mut self, handle: u32, size: Size) {
+        let size = u64::from(size.bytes());
+        if size == 0 || !self.is_enabled {
+            return;
+        }
+
+        if self.bytes_until_sample > size {
+            self.bytes_until_sample -= size;
+            return;
+        }
+
+        self.bytes_until_sample = self.next_sample_distance();
+
+        let mut frames = [0; MAX_FRAMES];
+        let depth = core::cmp::max(1, self.unwinder.unwind(&mut frames));
+        let Some(stack) = self.find_or_insert_stack(&frames[..depth]) else {
+            self.dropped_samples += 1;
+            return;
+        };
+
+        // Scale the sample to account for the allocations which weren't sampled.
+        let probability = 1.0 - exp_negative(size as f64 / self.sample_interval as f64);
+        let objects = (1.0 / probability + 0.5) as u64;
+        let space = (size as f64 / probability + 0.5) as u64;
+
+        if !self.insert_live_sample(LiveSample {
+            handle,
+            stack: stack as u32,
+            objects,
+            space,
+        }) {
+            self.dropped_samples += 1;
+            return;
+        }
+
+        let stack = &mut self.stacks[stack];
+        stack.alloc_objects += objects;
+        stack.alloc_space += space;
+        stack.inuse_objects += objects;
+        stack.inuse_space += space;
+    }
+
+    /// Writes the heap profile in the pprof protobuf format.
+    ///
+    /// The profile contains four sample types: `alloc_objects`, `alloc_space`, `inuse_objects` and `inuse_space`;
+    /// the first two are cumulative since the profiler was created, and the last two cover only the memory which is
+    /// still in use. The locations contain raw return addresses; on Linux they point to the executable mappings
+    /// of the process, which are read from `/proc/self/maps`, so that they can be symbolized against the binaries.
+    ///
+    /// When profiling the global allocator the `output` must not allocate memory through it.
+    pub fn write_pprof(&self, output: &mut impl TraceSink) {
+        let mut writer = ProtobufWriter { output };
+        let mappings = Mappings::load();
+
+        const STRINGS: [&str; 8] = [
+            "",
+            "alloc_objects",
+            "count",
+            "alloc_space",
+            "bytes",
+            "inuse_objects",
+            "inuse_space",
+            "space",
+        ];
+        const SAMPLE_TYPES: [(u64, u64); 4] = [(1, 2), (3, 4), (5, 2), (6, 4)];
+
+        for (kind, unit) in SAMPLE_TYPES {
+            writer.message(1, value_type_length(kind, unit), |writer| writer.value_type(kind, unit));
+        }
+
+        for (index, stack) in self.stacks.iter().enumerate() {
+            if stack.depth == 0 || stack.alloc_objects == 0 {
+                continue;
+            }
+
+            let values = [stack.alloc_objects, stack.alloc_space, stack.inuse_objects, stack.inuse_space];
+            let location_ids_length: usize = (0..stack.depth as usize)
+                .map(|frame| varint_length(location_id(index, frame)))
+                .sum();
+            let values_length: usize = values.iter().map(|&value| varint_length(value)).sum();
+            let length = field_length(1, location_ids_length) + field_length(2, values_length);
+
+            writer.message(2, length, |writer| {
+                writer.message(1, location_ids_length, |writer| {
+                    for frame in 0..stack.depth as usize {
+                        writer.varint(location_id(index, frame));
+                    }
+                });
+
+                writer.message(2, values_length, |writer| {
+                    for value in values {
+                        writer.varint(value);
+                    }
+                });
+            });
+        }
+
+        for (index, stack) in self.stacks.iter().enumerate() {
+            if stack.depth == 0 || stack.alloc_objects == 0 {
+                continue;
+            }
+
+            for (frame, &address) in stack.frames().iter().enumerate() {
+                let id = location_id(index, frame);
+                // Return addresses point after the call instruction, so point back into it.
+                let address = (address as u64).saturating_sub(1);
+                let mapping_id = mappings.find(address);
+                let mapping_id_length = mapping_id.map_or(0, |mapping_id| 1 + varint_length(mapping_id));
+                let length = 1 + varint_length(id) + mapping_id_length + 1 + varint_length(address);
+                writer.message(4, length, |writer| {
+                    writer.key(1, 0);
+                    writer.varint(id);
+                    if let Some(mapping_id) = mapping_id {
+                        writer.key(2, 0);
+                        writer.varint(mapping_id);
+                    }
+                    writer.key(3, 0);
+                    writer.varint(address);
+                });
+            }
+        }
+
+        for (index, (mapping, _)) in mappings.iter().enumerate() {
+            let fields = [
+                mapping_id(index),
+                mapping.start,
+                mapping.limit,
+                mapping.offset,
+                (STRINGS.len() + index) as u64,
+            ];
+            let length = fields.iter().map(|&value| 1 + varint_length(value)).sum();
+            writer.message(3, length, |writer| {
+                for (field, value) in (1..).zip(fields) {
+                    writer.key(field, 0);
+                    writer.varint(value);
+                }
+            });
+        }
+
+        for string in STRINGS {
+            writer.bytes(6, string.as_bytes());
+        }
+
+        for (_, path) in mappings.iter() {
+            writer.bytes(6, path);
+        }
+
+        writer.message(11, value_type_length(7, 4), |writer| writer.value_type(7, 4));
+        writer.key(12, 0);
+        writer.varint(self.sample_interval);
+    }
+}
+
+impl<E: Env, U: StackUnwinder> Env for Profiled<E, U> {
+    #[inline]
+    fn total_space(&self) -> Size {
+        self.env.total_space()
+    }
+
+    #[inline]
+    unsafe fn allocate_address_space(&mut self) -> *mut u8 {
+        self.env.allocate_address_space()
+    }
+
+    #[inline]
+    unsafe fn expand_memory_until(&mut self, base: *mut u8, size: Size) -> bool {
+        self.env.expand_memory_until(base, size)
+    }
+
+    #[inline]
+    unsafe fn free_address_space(&mut self, base: *mut u8) {
+        self.env.free_address_space(base)
+    }
+
+    #[inline]
+    unsafe fn decommit_memory_after(&mut self, base: *mut u8, size: Size) -> Option<Size> {
+        self.env.decommit_memory_after(base, size)
+    }
+
+    fn trace(&mut self, event: TraceEvent) {
+        match event {
+            TraceEvent::Alloc {
+                size,
+                result: Some(handle),
+                ..
+            } => self.on_alloc(handle, size),
+            TraceEvent::Alloc { result: None, .. } => {}
+            TraceEvent::Free { handle } => self.release(handle),
+            TraceEvent::Realloc { handle, size, result, .. } => {
+                if size.bytes() == 0 || result.is_some() {
+                    self.release(handle);
+                }
+
+                if let Some(result) = result {
+                    self.on_alloc(result, size);
+                }
+            }
+            TraceEvent::Grow { .. } => {}
+            TraceEvent::Shrink { handle, size } => {
+                if size.bytes() == 0 {
+                    self.release(handle);
+                }
+            }
+            TraceEvent::Reset => self.release_all(),
+        }
+
+        self.env.trace(event);
+    }
+}
+
+#[inline]
+fn location_id(stack: usize, frame: usize) -> u64 {
+    (stack * MAX_FRAMES + frame + 1) as u64
+}
+
+#[inline]
+fn mapping_id(index: usize) -> u64 {
+    (index + 1) as u64
+}
+
+#[inline]
+fn varint_length(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+#[inline]
+fn field_length(field: u32, length: usize) -> usize {
+    varint_length(u64::from(field << 3)) + varint_length(length as u64) + length
+}
+
+#[inline]
+fn value_type_length(kind: u64, unit: u64) -> usize {
+    1 + varint_length(kind) + 1 + varint_length(unit)
+}
+
+struct ProtobufWriter<'a, S> {
+    output: &'a mut S,
+}
+
+impl<S: TraceSink> ProtobufWriter<'_, S> {
+    fn varint(&mut self, mut value: u64) {
+        let mut buffer = [0; 10];
+        let mut length = 0;
+        while value >= 0x80 {
+            buffer[length] = (value as u8) | 0x80;
+            value >>= 7;
+            length += 1;
+        }
+
+        buffer[length] = value as u8;
+        self.output.write(&buffer[..=length]);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(u64::from(field << 3 | wire_type));
+    }
+
+    fn message(&mut self, field: u32, length: usize, callback: impl FnOnce(&mut Self)) {
+        self.key(field, 2);
+        self.varint(length as u64);
+        callback(self);
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, 2);
+        self.varint(bytes.len() as u64);
+        self.output.write(bytes);
+    }
+
+    fn value_type(&mut self, kind: u64, unit: u64) {
+        self.key(1, 0);
+        self.varint(kind);
+        self.key(2, 0);
+        self.varint(unit);
+    }
+}
+
+/// Calculates the natural logarithm of `x`; only valid for finite positive values.
+fn ln(x: f64) -> f64 {
+    const LN_2: f64 = core::f64::consts::LN_2;
+
+    // Split `x` into `mantissa * 2^exponent` where `mantissa` is in [1, 2).
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64 - 1023;
+    let mantissa = f64::from_bits((bits & ((1 << 52) - 1)) | (1023 << 52));
+
+    // ln(m) = 2 * atanh((m - 1) / (m + 1))
+    let s = (mantissa - 1.0) / (mantissa + 1.0);
+    let s2 = s * s;
+    let mut term = s;
+    let mut sum = 0.0;
+    let mut n = 1.0;
+    while n < 24.0 {
+        sum += term / n;
+        term *= s2;
+        n += 2.0;
+    }
+
+    exponent as f64 * LN_2 + 2.0 * sum
+}
+
+/// Calculates `e^(-x)`; only valid for finite non-negative values.
+fn exp_negative(x: f64) -> f64 {
+    const LN_2: f64 = core::f64::consts::LN_2;
+    if x > 700.0 {
+        return 0.0;
+    }
+
+    // e^(-x) = 2^(-k) * e^(-r) where x = k * ln(2) + r
+    let k = (x / LN_2) as u64;
+    let r = x - k as f64 * LN_2;
+
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    let mut n = 1.0;
+    while n < 20.0 {
+        term *= -r / n;
+        sum += term;
+        n += 1.0;
+    }
+
+    sum * f64::from_bits((1023 - k) << 52)
+}
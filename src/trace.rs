@@ -0,0 +1,316 @@
+use crate::{Env, Size};
+
+/// The magic bytes which every trace starts with.
+pub const TRACE_MAGIC: [u8; 8] = *b"picotrc1";
+
+/// A single operation performed by the allocator.
+///
+/// Allocations are identified by a handle, which is the offset of the allocation from the start
+/// of the heap divided by the allocation granularity. A handle stays unique for as long as
+/// the allocation it identifies is alive, and can be reused afterwards.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TraceEvent {
+    Alloc {
+        align: Size,
+        size: Size,
+        zeroed: bool,
+        result: Option<u32>,
+    },
+    Free {
+        handle: u32,
+    },
+    Realloc {
+        handle: u32,
+        align: Size,
+        size: Size,
+        result: Option<u32>,
+    },
+    Grow {
+        handle: u32,
+        size: Size,
+        result: Option<Size>,
+    },
+    Shrink {
+        handle: u32,
+        size: Size,
+    },
+    Reset,
+}
+
+const KIND_ALLOC: u8 = 0;
+const KIND_FREE: u8 = 1;
+const KIND_REALLOC: u8 = 2;
+const KIND_GROW: u8 = 3;
+const KIND_SHRINK: u8 = 4;
+const KIND_RESET: u8 = 5;
+
+const KIND_MASK: u8 = 0b1111;
+const FLAG_ZEROED: u8 = 1 << 4;
+const FLAG_HAS_RESULT: u8 = 1 << 5;
+
+const MAX_VARINT_LENGTH: usize = 5;
+
+struct Writer<'a> {
+    buffer: &'a mut [u8; TraceEvent::MAX_ENCODED_LENGTH],
+    position: usize,
+}
+
+impl Writer<'_> {
+    #[inline]
+    fn push(&mut self, byte: u8) {
+        self.buffer[self.position] = byte;
+        self.position += 1;
+    }
+
+    #[inline]
+    fn push_varint(&mut self, mut value: u32) {
+        while value >= 0x80 {
+            self.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+
+        self.push(value as u8);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    #[inline]
+    fn pop(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    #[inline]
+    fn pop_varint(&mut self) -> Option<u32> {
+        let mut value: u32 = 0;
+        for nth in 0..MAX_VARINT_LENGTH {
+            let byte = self.pop()?;
+            let chunk = u32::from(byte & 0x7f);
+            if nth == MAX_VARINT_LENGTH - 1 && chunk > 0b1111 {
+                return None;
+            }
+
+            value |= chunk << (nth * 7);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+
+    #[inline]
+    fn pop_size(&mut self) -> Option<Size> {
+        self.pop_varint().map(Size::from_granules)
+    }
+}
+
+impl TraceEvent {
+    /// The maximum number of bytes a single event can take when encoded.
+    pub const MAX_ENCODED_LENGTH: usize = 1 + 4 * MAX_VARINT_LENGTH;
+
+    /// Encodes the event into `buffer` and returns the number of bytes written.
+    pub fn encode(&self, buffer: &mut [u8; Self::MAX_ENCODED_LENGTH]) -> usize {
+        let mut writer = Writer { buffer, position: 0 };
+        match *self {
+            TraceEvent::Alloc {
+                align,
+                size,
+                zeroed,
+                result,
+            } => {
+                let mut tag = KIND_ALLOC;
+                if zeroed {
+                    tag |= FLAG_ZEROED;
+                }
+                if result.is_some() {
+                    tag |= FLAG_HAS_RESULT;
+                }
+
+                writer.push(tag);
+                writer.push_varint(align.granules());
+                writer.push_varint(size.granules());
+                if let Some(result) = result {
+                    writer.push_varint(result);
+                }
+            }
+            TraceEvent::Free { handle } => {
+                writer.push(KIND_FREE);
+                writer.push_varint(handle);
+            }
+            TraceEvent::Realloc {
+                handle,
+                align,
+                size,
+                result,
+            } => {
+                writer.push(if result.is_some() {
+                    KIND_REALLOC | FLAG_HAS_RESULT
+                } else {
+                    KIND_REALLOC
+                });
+                writer.push_varint(handle);
+                writer.push_varint(align.granules());
+                writer.push_varint(size.granules());
+                if let Some(result) = result {
+                    writer.push_varint(result);
+                }
+            }
+            TraceEvent::Grow { handle, size, result } => {
+                writer.push(if result.is_some() { KIND_GROW | FLAG_HAS_RESULT } else { KIND_GROW });
+                writer.push_varint(handle);
+                writer.push_varint(size.granules());
+                if let Some(result) = result {
+                    writer.push_varint(result.granules());
+                }
+            }
+            TraceEvent::Shrink { handle, size } => {
+                writer.push(KIND_SHRINK);
+                writer.push_varint(handle);
+                writer.push_varint(size.granules());
+            }
+            TraceEvent::Reset => writer.push(KIND_RESET),
+        }
+
+        writer.position
+    }
+
+    /// Decodes a single event from the start of `bytes`.
+    ///
+    /// Returns the event and the number of bytes it took, or `None` if the input is truncated or malformed.
+    pub fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let mut reader = Reader { bytes, position: 0 };
+        let tag = reader.pop()?;
+        let has_result = tag & FLAG_HAS_RESULT != 0;
+        let event = match tag & KIND_MASK {
+            KIND_ALLOC => {
+                let align = reader.pop_size()?;
+                let size = reader.pop_size()?;
+                let result = if has_result { Some(reader.pop_varint()?) } else { None };
+                TraceEvent::Alloc {
+                    align,
+                    size,
+                    zeroed: tag & FLAG_ZEROED != 0,
+                    result,
+                }
+            }
+            KIND_FREE => TraceEvent::Free {
+                handle: reader.pop_varint()?,
+            },
+            KIND_REALLOC => {
+                let handle = reader.pop_varint()?;
+                let align = reader.pop_size()?;
+                let size = reader.pop_size()?;
+                let result = if has_result { Some(reader.pop_varint()?) } else { None };
+                TraceEvent::Realloc {
+                    handle,
+                    align,
+                    size,
+                    result,
+                }
+            }
+            KIND_GROW => {
+                let handle = reader.pop_varint()?;
+                let size = reader.pop_size()?;
+                let result = if has_result { Some(reader.pop_size()?) } else { None };
+                TraceEvent::Grow { handle, size, result }
+            }
+            KIND_SHRINK => {
+                let handle = reader.pop_varint()?;
+                let size = reader.pop_size()?;
+                TraceEvent::Shrink { handle, size }
+            }
+            KIND_RESET => TraceEvent::Reset,
+            _ => return None,
+        };
+
+        Some((event, reader.position))
+    }
+}
+
+/// A destination for an encoded trace.
+pub trait TraceSink {
+    fn write(&mut self, bytes: &[u8]);
+}
+
+impl<F> TraceSink for F
+where
+    F: FnMut(&[u8]),
+{
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        self(bytes)
+    }
+}
+
+/// An environment which records every operation of the allocator into a binary trace.
+///
+/// The trace starts with [`TRACE_MAGIC`] followed by a stream of events encoded with [`TraceEvent::encode`].
+pub struct Traced<E, S> {
+    env: E,
+    sink: S,
+    is_magic_written: bool,
+}
+
+impl<E, S> Traced<E, S> {
+    pub const fn new(env: E, sink: S) -> Self {
+        Traced {
+            env,
+            sink,
+            is_magic_written: false,
+        }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<E: Env, S: TraceSink> Env for Traced<E, S> {
+    #[inline]
+    fn total_space(&self) -> Size {
+        self.env.total_space()
+    }
+
+    #[inline]
+    unsafe fn allocate_address_space(&mut self) -> *mut u8 {
+        self.env.allocate_address_space()
+    }
+
+    #[inline]
+    unsafe fn expand_memory_until(&mut self, base: *mut u8, size: Size) -> bool {
+        self.env.expand_memory_until(base, size)
+    }
+
+    #[inline]
+    unsafe fn free_address_space(&mut self, base: *mut u8) {
+        self.env.free_address_space(base)
+    }
+
+    #[inline]
+    unsafe fn decommit_memory_after(&mut self, base: *mut u8, size: Size) -> Option<Size> {
+        self.env.decommit_memory_after(base, size)
+    }
+
+    fn trace(&mut self, event: TraceEvent) {
+        if !self.is_magic_written {
+            self.sink.write(&TRACE_MAGIC);
+            self.is_magic_written = true;
+        }
+
+        let mut buffer = [0; TraceEvent::MAX_ENCODED_LENGTH];
+        let length = event.encode(&mut buffer);
+        self.sink.write(&buffer[..length]);
+        self.env.trace(event);
+    }
+}